@@ -0,0 +1,131 @@
+//! Live feed of what the controller is doing
+//!
+//! The controller publishes an event to a broadcast channel at each step it takes, and `/events`
+//! streams them to clients as server-sent events. Nothing is kept: a client only sees what happens
+//! while it is connected, and one that falls too far behind skips ahead.
+
+use crate::deploylog;
+use crate::state::{Outcome, TriggerSource};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// How many events a slow client can fall behind by before it misses some
+const CAPACITY: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Activity {
+    TriggerReceived {
+        trigger: TriggerSource,
+    },
+    PullStarted {
+        image: String,
+    },
+    PullFinished {
+        image: String,
+    },
+    ContainerStopped {
+        container: String,
+    },
+    ContainerStarted {
+        container: String,
+        image: String,
+    },
+    PollResult {
+        running: bool,
+        /// Replicas that were not running
+        down: Vec<String>,
+    },
+    ConfigReloaded {
+        container: String,
+        image: String,
+    },
+    RollbackStarted {
+        image: String,
+    },
+    DeployFinished {
+        outcome: Outcome,
+        error: Option<String>,
+    },
+}
+
+impl Activity {
+    /// The SSE event name, the same as the `type` field
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Activity::TriggerReceived { .. } => "trigger_received",
+            Activity::PullStarted { .. } => "pull_started",
+            Activity::PullFinished { .. } => "pull_finished",
+            Activity::ContainerStopped { .. } => "container_stopped",
+            Activity::ContainerStarted { .. } => "container_started",
+            Activity::PollResult { .. } => "poll_result",
+            Activity::ConfigReloaded { .. } => "config_reloaded",
+            Activity::RollbackStarted { .. } => "rollback_started",
+            Activity::DeployFinished { .. } => "deploy_finished",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Event {
+    pub(crate) time: DateTime<Utc>,
+    /// The deployment the event is part of, if any
+    pub(crate) deploy_id: Option<Uuid>,
+    #[serde(flatten)]
+    pub(crate) activity: Activity,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Publisher(broadcast::Sender<Event>);
+
+impl Publisher {
+    pub(crate) fn new() -> Self {
+        let (tx, _) = broadcast::channel(CAPACITY);
+        Publisher(tx)
+    }
+
+    pub(crate) fn publish(&self, activity: Activity) {
+        let event = Event {
+            time: Utc::now(),
+            deploy_id: deploylog::current_id(),
+            activity,
+        };
+        // An error only means nobody is listening
+        let _ = self.0.send(event);
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish() {
+        let publisher = Publisher::new();
+        // Publishing with no subscribers is fine
+        publisher.publish(Activity::PullStarted {
+            image: "python:3.8".to_string(),
+        });
+
+        let mut rx = publisher.subscribe();
+        let id = Uuid::new_v4();
+        deploylog::scope(id, Default::default(), async {
+            publisher.publish(Activity::TriggerReceived {
+                trigger: TriggerSource::Api,
+            })
+        })
+        .await;
+
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.deploy_id, Some(id));
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], event.activity.name());
+        assert_eq!(json["trigger"], "api");
+    }
+}
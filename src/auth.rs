@@ -0,0 +1,344 @@
+//! API authentication
+//!
+//! Requests carry one of the `[auth]` tokens, either as an `Authorization: Bearer` header or, for
+//! browser event streams which cannot set headers, an `access_token` query parameter. Tokens are
+//! compared by their hashes in constant time, so how long a check takes says nothing about how
+//! close a guess was. Everything a token changes, and every refused request, goes into the audit
+//! log. The guard writes it into the shared state itself, as going through the controller would
+//! leave entries queueing up behind a deploy.
+
+use crate::config::{AuthConfig, Scope, TokenConfig};
+use crate::state::SharedState;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::fmt;
+use warp::http::{Method, StatusCode};
+use warp::path::FullPath;
+use warp::{Filter, Rejection};
+
+/// One request that changed something or was refused, as returned by `/audit`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct AuditEntry {
+    pub(crate) time: DateTime<Utc>,
+    /// The name of the token used, if it was a known one
+    pub(crate) token: Option<String>,
+    /// e.g. `POST /trigger`
+    pub(crate) action: String,
+    pub(crate) scope: Scope,
+    pub(crate) allowed: bool,
+    /// Why the request was refused
+    pub(crate) reason: Option<String>,
+}
+
+/// Why a request was refused, turned into a response by `handlers::handle_rejection`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Denied {
+    MissingToken,
+    UnknownToken,
+    MissingScope { token: String, scope: Scope },
+    WrongService { token: String, service: String },
+}
+
+impl Denied {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            Denied::MissingToken | Denied::UnknownToken => StatusCode::UNAUTHORIZED,
+            Denied::MissingScope { .. } | Denied::WrongService { .. } => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn token(&self) -> Option<String> {
+        match self {
+            Denied::MissingToken | Denied::UnknownToken => None,
+            Denied::MissingScope { token, .. } | Denied::WrongService { token, .. } => {
+                Some(token.clone())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denied::MissingToken => f.write_str("a bearer token is required"),
+            Denied::UnknownToken => f.write_str("unknown token"),
+            Denied::MissingScope { token, scope } => {
+                write!(f, "token {} does not have the {} scope", token, scope)
+            }
+            Denied::WrongService { token, service } => {
+                write!(f, "token {} is not allowed for service {}", token, service)
+            }
+        }
+    }
+}
+
+impl warp::reject::Reject for Denied {}
+
+#[derive(Deserialize, Debug)]
+struct TokenQuery {
+    access_token: Option<String>,
+}
+
+/// Builds filters that only let requests through with a token allowed to do what the route does
+#[derive(Clone)]
+pub(crate) struct Guard {
+    state: SharedState,
+}
+
+impl Guard {
+    pub(crate) fn new(state: SharedState) -> Self {
+        Guard { state }
+    }
+
+    pub(crate) fn require(
+        &self,
+        scope: Scope,
+    ) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        let guard = self.clone();
+
+        warp::method()
+            .and(warp::path::full())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::query::<TokenQuery>())
+            .and_then(
+                move |method: Method, path: FullPath, header: Option<String>, query: TokenQuery| {
+                    let token = bearer(header.as_deref())
+                        .map(str::to_string)
+                        .or(query.access_token);
+                    let action = format!("{} {}", method, path.as_str());
+                    let res = guard.check(token.as_deref(), scope, action);
+                    async move { res.map_err(warp::reject::custom) }
+                },
+            )
+            .untuple_one()
+    }
+
+    fn check(&self, token: Option<&str>, scope: Scope, action: String) -> Result<(), Denied> {
+        let res = {
+            let state = self.state.read().unwrap();
+            match &state.auth {
+                Some(auth) => authorize(auth, token, scope, &state.container)
+                    .map(|token| Some(token.name.clone())),
+                // Without an `[auth]` section everything is allowed, and there is nothing to audit
+                None => return Ok(()),
+            }
+        };
+
+        let (token, reason) = match &res {
+            Ok(token) => (token.clone(), None),
+            Err(denied) => (denied.token(), Some(denied.to_string())),
+        };
+        let entry = AuditEntry {
+            time: Utc::now(),
+            token,
+            action,
+            scope,
+            allowed: res.is_ok(),
+            reason,
+        };
+        match &entry.reason {
+            Some(reason) => log::warn!("refused {}: {}", entry.action, reason),
+            // Reads are too frequent to be worth keeping, e.g. the dashboard polls the status
+            None if scope == Scope::Read => return Ok(()),
+            None => log::info!(
+                "{} allowed for token {}",
+                entry.action,
+                entry.token.as_deref().unwrap_or("-")
+            ),
+        }
+        audit(&self.state, entry);
+
+        res.map(|_| ())
+    }
+}
+
+/// Add an entry to the audit log, which is only kept when there is an `[auth]` section
+pub(crate) fn audit(state: &SharedState, entry: AuditEntry) {
+    let mut state = state.write().unwrap();
+    if state.auth.is_some() {
+        state.record_audit(entry);
+    }
+}
+
+/// Whether a secret given in a request is the configured one, compared like the tokens
+pub(crate) fn secret_matches(given: &str, expected: &str) -> bool {
+    constant_time_eq(
+        &Sha1::digest(given.as_bytes()),
+        &Sha1::digest(expected.as_bytes()),
+    )
+}
+
+/// The token in an `Authorization: Bearer <token>` header
+fn bearer(header: Option<&str>) -> Option<&str> {
+    let header = header?.trim();
+    let space = header.find(' ')?;
+    let (scheme, token) = header.split_at(space);
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// The token allowed to act with `scope` on `service`
+pub(crate) fn authorize<'a>(
+    auth: &'a AuthConfig,
+    token: Option<&str>,
+    scope: Scope,
+    service: &str,
+) -> Result<&'a TokenConfig, Denied> {
+    let token = find_token(auth, token.ok_or(Denied::MissingToken)?).ok_or(Denied::UnknownToken)?;
+
+    if !token
+        .scopes
+        .iter()
+        .any(|s| *s == scope || *s == Scope::Admin)
+    {
+        return Err(Denied::MissingScope {
+            token: token.name.clone(),
+            scope,
+        });
+    }
+    if let Some(services) = &token.services {
+        if !services.iter().any(|s| s == service) {
+            return Err(Denied::WrongService {
+                token: token.name.clone(),
+                service: service.to_string(),
+            });
+        }
+    }
+    Ok(token)
+}
+
+fn find_token<'a>(auth: &'a AuthConfig, given: &str) -> Option<&'a TokenConfig> {
+    // Comparing hashes hides the tokens' lengths, and checking every token hides which matched
+    let given = Sha1::digest(given.as_bytes());
+    auth.tokens.iter().fold(None, |found, token| {
+        let matches = constant_time_eq(&Sha1::digest(token.token.as_bytes()), &given);
+        found.or(if matches { Some(token) } else { None })
+    })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> AuthConfig {
+        toml::from_str(
+            r#"
+            [[tokens]]
+            name = "ci"
+            token = "ci-0123456789abcdef"
+            scopes = ["deploy"]
+            services = ["web"]
+
+            [[tokens]]
+            name = "ops"
+            token = "ops-0123456789abcdef"
+            scopes = ["admin"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_bearer() {
+        assert_eq!(bearer(Some("Bearer abc")), Some("abc"));
+        assert_eq!(bearer(Some("bearer  abc ")), Some("abc"));
+        assert_eq!(bearer(Some("Basic abc")), None);
+        assert_eq!(bearer(Some("abc")), None);
+        assert_eq!(bearer(None), None);
+    }
+
+    #[test]
+    fn test_authorize() {
+        let auth = auth();
+        let check = |token: Option<&str>, scope: Scope, service: &str| {
+            authorize(&auth, token, scope, service).map(|t| t.name.clone())
+        };
+
+        assert_eq!(
+            check(Some("ci-0123456789abcdef"), Scope::Deploy, "web"),
+            Ok("ci".to_string())
+        );
+        assert_eq!(
+            check(Some("ops-0123456789abcdef"), Scope::Rollback, "api"),
+            Ok("ops".to_string())
+        );
+
+        assert_eq!(check(None, Scope::Read, "web"), Err(Denied::MissingToken));
+        assert_eq!(
+            check(Some("ci-0123456789abcdeX"), Scope::Deploy, "web"),
+            Err(Denied::UnknownToken)
+        );
+        let denied = check(Some("ci-0123456789abcdef"), Scope::Rollback, "web").unwrap_err();
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            denied.to_string(),
+            "token ci does not have the rollback scope"
+        );
+        assert!(matches!(
+            check(Some("ci-0123456789abcdef"), Scope::Deploy, "api"),
+            Err(Denied::WrongService { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_guard() {
+        let mut state = crate::state::State::new("web", "web:latest");
+        state.auth = Some(auth());
+        let state = state.shared();
+        let guard = Guard::new(state.clone());
+        let deploy = guard.require(Scope::Deploy);
+
+        let refused = warp::test::request()
+            .method("POST")
+            .path("/trigger")
+            .filter(&deploy)
+            .await
+            .unwrap_err();
+        assert_eq!(refused.find::<Denied>(), Some(&Denied::MissingToken));
+        {
+            let state = state.read().unwrap();
+            let entry = &state.audit()[0];
+            assert_eq!(entry.action, "POST /trigger");
+            assert!(!entry.allowed);
+        }
+
+        warp::test::request()
+            .method("POST")
+            .path("/trigger")
+            .header("authorization", "Bearer ci-0123456789abcdef")
+            .filter(&deploy)
+            .await
+            .unwrap();
+        {
+            let state = state.read().unwrap();
+            let entry = &state.audit()[0];
+            assert_eq!(entry.token.as_deref(), Some("ci"));
+            assert!(entry.allowed);
+        }
+
+        // Event streams pass the token in the query, and reads are not audited
+        warp::test::request()
+            .path("/events?access_token=ops-0123456789abcdef")
+            .filter(&guard.require(Scope::Read))
+            .await
+            .unwrap();
+        assert_eq!(state.read().unwrap().audit().len(), 2);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+        assert!(secret_matches("my-key", "my-key"));
+        assert!(!secret_matches("my-ke", "my-key"));
+    }
+}
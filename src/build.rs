@@ -0,0 +1,143 @@
+//! Building the image from a git repository
+//!
+//! The configured ref is fetched into a bare repository kept between builds, so later fetches only
+//! transfer what changed. The commit's tracked files are sent to docker as the build context,
+//! which leaves out anything untracked and the repository's history.
+
+use crate::config::{BuildConfig, ImageConfig};
+use crate::dockerclient::{BuildOptions, DockerApi};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tokio::stream::StreamExt;
+
+/// Fetch and build the configured ref, tagging the result with the configured image. Returns the
+/// commit that was built.
+pub(crate) async fn build<D: DockerApi>(
+    docker: &D,
+    config: &BuildConfig,
+    image: &ImageConfig,
+    service: &str,
+) -> Result<String> {
+    let checkout = match &config.checkout_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::temp_dir().join("dockerdeploy").join(service),
+    };
+
+    log::info!("fetching {} from {}", config.git_ref, config.repo);
+    let fetch_config = config.clone();
+    let (commit, context) =
+        tokio::task::spawn_blocking(move || fetch(&fetch_config, &checkout)).await??;
+
+    log::info!("building {} from commit {}", image.reference(), commit);
+    let options = BuildOptions {
+        dockerfile: config.dockerfile.clone(),
+        tags: vec![image.reference()],
+        args: config.args.clone(),
+        target: config.target.clone(),
+    };
+    let mut output = docker.build_image(options, context);
+    while let Some(chunk) = output.next().await {
+        let chunk = chunk.with_context(|| format!("building commit {}", commit))?;
+        for line in chunk.lines().filter(|line| !line.trim().is_empty()) {
+            log::info!("build: {}", line);
+        }
+    }
+
+    Ok(commit)
+}
+
+/// Fetch the ref into the repository at `checkout`, returning the commit and a tar of its files
+fn fetch(config: &BuildConfig, checkout: &Path) -> Result<(String, Vec<u8>)> {
+    if !checkout.join("HEAD").exists() {
+        std::fs::create_dir_all(checkout)
+            .with_context(|| format!("creating {}", checkout.display()))?;
+        git(checkout, &["init", "--quiet", "--bare"])?;
+    }
+
+    git(
+        checkout,
+        &["fetch", "--quiet", "--force", &config.repo, &config.git_ref],
+    )
+    .with_context(|| format!("fetching {} from {}", config.git_ref, config.repo))?;
+    let commit = git(checkout, &["rev-parse", "FETCH_HEAD^{commit}"])?;
+    let commit = String::from_utf8(commit)?.trim().to_string();
+
+    let context = git(checkout, &["archive", "--format=tar", &commit])?;
+    Ok((commit, context))
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .context("running git")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fetch() {
+        let root = std::env::temp_dir().join(format!("dockerdeploy-test-{}", uuid::Uuid::new_v4()));
+        let repo = root.join("repo");
+        let checkout = root.join("checkout");
+        std::fs::create_dir_all(&repo).unwrap();
+
+        let commit_all = |message: &str| {
+            git(&repo, &["add", "."]).unwrap();
+            git(
+                &repo,
+                &[
+                    "-c",
+                    "user.name=test",
+                    "-c",
+                    "user.email=test@example.com",
+                    "commit",
+                    "--quiet",
+                    "-m",
+                    message,
+                ],
+            )
+            .unwrap();
+        };
+        git(&repo, &["init", "--quiet"]).unwrap();
+        std::fs::write(repo.join("Dockerfile"), "FROM scratch\n").unwrap();
+        commit_all("first");
+        git(&repo, &["tag", "v1"]).unwrap();
+        std::fs::write(repo.join("app.txt"), "hello\n").unwrap();
+        commit_all("second");
+        std::fs::write(repo.join("untracked.txt"), "left out\n").unwrap();
+
+        let mut config: BuildConfig =
+            toml::from_str(&format!("repo = \"{}\"", repo.display())).unwrap();
+        let (head, context) = fetch(&config, &checkout).unwrap();
+        let head_expected = git(&repo, &["rev-parse", "HEAD"]).unwrap();
+        assert_eq!(head, String::from_utf8(head_expected).unwrap().trim());
+        let context = String::from_utf8_lossy(&context);
+        assert!(context.contains("app.txt"));
+        assert!(!context.contains("untracked.txt"));
+
+        // Fetching again reuses the same repository
+        config.git_ref = "v1".to_string();
+        let (tagged, context) = fetch(&config, &checkout).unwrap();
+        assert_ne!(tagged, head);
+        assert!(!String::from_utf8_lossy(&context).contains("app.txt"));
+
+        config.git_ref = "missing".to_string();
+        assert!(fetch(&config, &checkout).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+//! Canary deployments
+//!
+//! The new image runs in a second container next to the old one for a soak period, with its own
+//! name and host ports. It is checked at a fixed interval, and the deploy only goes ahead if it
+//! stays up, healthy and answering its probe for the whole period.
+
+use crate::config::CanaryConfig;
+use crate::dockerclient::{ContainerDetails, DockerApi};
+use crate::readiness;
+use anyhow::{Context, Result};
+use std::time::Duration;
+
+/// e.g. `foobar-canary`
+pub(crate) fn container_name(service: &str, config: &CanaryConfig) -> String {
+    format!("{}-{}", service, config.suffix)
+}
+
+/// Watch the canary for the configured duration, failing as soon as it misbehaves
+pub(crate) async fn soak<D: DockerApi>(
+    docker: &D,
+    name: &str,
+    config: &CanaryConfig,
+) -> Result<()> {
+    if let Some(probe) = &config.probe {
+        log::info!("waiting for canary {} to become ready", name);
+        readiness::wait(probe)
+            .await
+            .context("canary never became ready")?;
+    }
+
+    let client = match &config.probe {
+        Some(probe) => Some(readiness::client(probe)?),
+        None => None,
+    };
+    let interval = Duration::from_secs(config.interval);
+    let mut tracker = Tracker::new(config);
+
+    log::info!("soaking canary {} for {}s", name, config.duration);
+    while !tracker.done() {
+        tokio::time::delay_for(interval).await;
+
+        let details = docker
+            .inspect_container(name)
+            .await
+            .context("inspecting canary")?;
+        let probe = match (&client, &config.probe) {
+            (Some(client), Some(probe)) => Some(readiness::probe(client, probe).await),
+            _ => None,
+        };
+        tracker.check(&details, probe)?;
+    }
+
+    log::info!(
+        "canary {} passed {} checks, {} probe failures",
+        name,
+        tracker.checks,
+        tracker.failures
+    );
+    Ok(())
+}
+
+/// Tallies the checks made on a canary against its limits
+struct Tracker {
+    planned: u64,
+    checks: u64,
+    failures: u64,
+    allowed_failures: u64,
+    max_restarts: u64,
+}
+
+impl Tracker {
+    fn new(config: &CanaryConfig) -> Self {
+        let planned = (config.duration / config.interval).max(1);
+        Tracker {
+            planned,
+            checks: 0,
+            failures: 0,
+            allowed_failures: (config.max_error_rate * planned as f64).floor() as u64,
+            max_restarts: config.max_restarts,
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.checks >= self.planned
+    }
+
+    fn check(&mut self, details: &ContainerDetails, probe: Option<Result<()>>) -> Result<()> {
+        self.checks += 1;
+
+        if !details.running {
+            anyhow::bail!("canary exited with status {}", details.exit_code);
+        }
+        if details.restart_count > self.max_restarts {
+            anyhow::bail!("canary restarted {} times", details.restart_count);
+        }
+        if details.health.as_deref() == Some("unhealthy") {
+            anyhow::bail!("canary is unhealthy");
+        }
+        if let Some(Err(e)) = probe {
+            self.failures += 1;
+            log::warn!(
+                "canary probe failed ({} of {} allowed): {:#}",
+                self.failures,
+                self.allowed_failures,
+                e
+            );
+            if self.failures > self.allowed_failures {
+                return Err(e.context(format!(
+                    "{} of {} canary probes failed",
+                    self.failures, self.checks
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_error_rate: f64) -> CanaryConfig {
+        CanaryConfig {
+            suffix: "canary".to_string(),
+            ports: Vec::new(),
+            duration: 100,
+            interval: 10,
+            probe: None,
+            max_error_rate,
+            max_restarts: 1,
+        }
+    }
+
+    fn running() -> ContainerDetails {
+        ContainerDetails {
+            running: true,
+            restart_count: 0,
+            health: None,
+            exit_code: 0,
+            oom_killed: false,
+            finished_at: None,
+            error: None,
+        }
+    }
+
+    fn failed_probe() -> Option<Result<()>> {
+        Some(Err(anyhow::anyhow!("connection refused")))
+    }
+
+    #[test]
+    fn test_error_rate() {
+        let mut tracker = Tracker::new(&config(0.2));
+        assert_eq!(tracker.allowed_failures, 2);
+
+        assert!(tracker.check(&running(), failed_probe()).is_ok());
+        assert!(tracker.check(&running(), Some(Ok(()))).is_ok());
+        assert!(tracker.check(&running(), failed_probe()).is_ok());
+        assert!(tracker.check(&running(), failed_probe()).is_err());
+    }
+
+    #[test]
+    fn test_container_state() {
+        let mut tracker = Tracker::new(&config(0.0));
+
+        let mut restarted = running();
+        restarted.restart_count = 1;
+        assert!(tracker.check(&restarted, None).is_ok());
+        restarted.restart_count = 2;
+        assert!(tracker.check(&restarted, None).is_err());
+
+        let mut unhealthy = running();
+        unhealthy.health = Some("unhealthy".to_string());
+        assert!(tracker.check(&unhealthy, None).is_err());
+
+        let mut exited = running();
+        exited.running = false;
+        assert!(tracker.check(&exited, None).is_err());
+    }
+
+    #[test]
+    fn test_done() {
+        let mut tracker = Tracker::new(&config(0.0));
+        for _ in 0..10 {
+            assert!(!tracker.done());
+            tracker.check(&running(), Some(Ok(()))).unwrap();
+        }
+        assert!(tracker.done());
+    }
+}
@@ -0,0 +1,413 @@
+//! Subcommands for talking to a running daemon over its HTTP API
+
+use crate::config::DockerDeployConfig;
+use crate::state::{Deployment, DeploymentDetails, Status};
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub(crate) enum Command {
+    /// Show the state of the managed container
+    Status(ClientOpts),
+    /// Pull the configured image and replace the running container
+    Deploy(ClientOpts),
+    /// Replace the running container with the previously deployed image
+    Rollback(ClientOpts),
+    /// List recent deployments
+    History(ClientOpts),
+    /// Show a deployment and what it logged
+    Show {
+        #[structopt(help = "Deployment id, as listed by `history`")]
+        id: uuid::Uuid,
+
+        #[structopt(flatten)]
+        client: ClientOpts,
+    },
+    /// Print the managed container's logs
+    Logs {
+        #[structopt(long, help = "Only print this many lines from the end of the logs")]
+        tail: Option<u64>,
+
+        #[structopt(short, long, help = "Keep printing new output as it is written")]
+        follow: bool,
+
+        #[structopt(
+            long,
+            help = "Only print output since this unix timestamp or RFC 3339 time"
+        )]
+        since: Option<String>,
+
+        #[structopt(flatten)]
+        client: ClientOpts,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct ClientOpts {
+    #[structopt(
+        short,
+        long,
+        help = "Daemon address, e.g. http://127.0.0.1:8080. Defaults to the config file's [server]"
+    )]
+    server: Option<String>,
+
+    #[structopt(
+        long,
+        help = "API token, if the daemon has an [auth] section. Defaults to $DOCKERDEPLOY_TOKEN"
+    )]
+    token: Option<String>,
+
+    #[structopt(
+        short,
+        long,
+        default_value = "table",
+        help = "Output format: table or json"
+    )]
+    output: OutputFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum OutputFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            other => anyhow::bail!("unknown output format `{}`", other),
+        }
+    }
+}
+
+pub(crate) async fn run(command: Command, config: Option<&Path>) -> Result<()> {
+    match command {
+        Command::Status(opts) => {
+            let client = Client::new(&opts, config)?;
+            let status: Status = client.get("status").await?.json().await?;
+            match opts.output {
+                OutputFormat::Json => print_json(&status)?,
+                OutputFormat::Table => print!("{}", format_status(&status)),
+            }
+        }
+        Command::Deploy(opts) => {
+            Client::new(&opts, config)?.post("trigger").await?;
+            print_requested("deploy", opts.output);
+        }
+        Command::Rollback(opts) => {
+            Client::new(&opts, config)?.post("rollback").await?;
+            print_requested("rollback", opts.output);
+        }
+        Command::History(opts) => {
+            let client = Client::new(&opts, config)?;
+            let history: Vec<Deployment> = client.get("history").await?.json().await?;
+            match opts.output {
+                OutputFormat::Json => print_json(&history)?,
+                OutputFormat::Table => print!("{}", format_history(&history)),
+            }
+        }
+        Command::Show { id, client: opts } => {
+            let client = Client::new(&opts, config)?;
+            let details: DeploymentDetails = client
+                .get(&format!("deployments/{}", id))
+                .await?
+                .json()
+                .await?;
+            match opts.output {
+                OutputFormat::Json => print_json(&details)?,
+                OutputFormat::Table => print!("{}", format_details(&details)),
+            }
+        }
+        Command::Logs {
+            tail,
+            follow,
+            since,
+            client: opts,
+        } => {
+            let client = Client::new(&opts, config)?;
+            let mut query = vec![("follow", follow.to_string())];
+            if let Some(n) = tail {
+                query.push(("tail", n.to_string()));
+            }
+            if let Some(since) = since {
+                query.push(("since", since));
+            }
+            let mut res = client.get_with_query("logs", &query).await?;
+
+            // Chunks do not line up with lines, so hold back any partial line until the rest of
+            // it arrives
+            let mut pending = String::new();
+            while let Some(chunk) = res.chunk().await? {
+                pending.push_str(&String::from_utf8_lossy(&chunk));
+                while let Some(end) = pending.find('\n') {
+                    let line: String = pending.drain(..=end).collect();
+                    print_log_line(&line, opts.output)?;
+                }
+            }
+            if !pending.is_empty() {
+                print_log_line(&pending, opts.output)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+struct Client {
+    base_url: String,
+    token: Option<String>,
+    http: reqwest::Client,
+}
+
+impl Client {
+    fn new(opts: &ClientOpts, config: Option<&Path>) -> Result<Self> {
+        let base_url = match (&opts.server, config) {
+            (Some(server), _) => server.trim_end_matches('/').to_string(),
+            (None, Some(path)) => {
+                let config = DockerDeployConfig::from_file(path).context("reading config file")?;
+                format!("http://{}", config.listen_address()?)
+            }
+            (None, None) => anyhow::bail!("either --server or --config must be given"),
+        };
+
+        Ok(Client {
+            base_url,
+            token: opts
+                .token
+                .clone()
+                .or_else(|| std::env::var("DOCKERDEPLOY_TOKEN").ok()),
+            http: reqwest::Client::new(),
+        })
+    }
+
+    async fn get(&self, path: &str) -> Result<reqwest::Response> {
+        let url = format!("{}/{}", self.base_url, path);
+        let res = self
+            .authorized(self.http.get(&url))
+            .send()
+            .await
+            .with_context(|| format!("requesting {}", url))?;
+        Ok(res.error_for_status()?)
+    }
+
+    /// Like `get`, with `query` encoded onto the URL
+    async fn get_with_query<Q: serde::Serialize + ?Sized>(
+        &self,
+        path: &str,
+        query: &Q,
+    ) -> Result<reqwest::Response> {
+        let url = format!("{}/{}", self.base_url, path);
+        let res = self
+            .authorized(self.http.get(&url).query(query))
+            .send()
+            .await
+            .with_context(|| format!("requesting {}", url))?;
+        Ok(res.error_for_status()?)
+    }
+
+    async fn post(&self, path: &str) -> Result<reqwest::Response> {
+        let url = format!("{}/{}", self.base_url, path);
+        let res = self
+            .authorized(self.http.post(&url))
+            .send()
+            .await
+            .with_context(|| format!("requesting {}", url))?;
+        Ok(res.error_for_status()?)
+    }
+
+    fn authorized(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+fn print_json<T: serde::Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// Print a line of log output, as a JSON string per line for json output
+fn print_log_line(line: &str, output: OutputFormat) -> Result<()> {
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string(line.trim_end_matches('\n'))?),
+        OutputFormat::Table => print!("{}", line),
+    }
+    Ok(())
+}
+
+fn print_requested(action: &str, output: OutputFormat) {
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::json!({ "requested": action })),
+        OutputFormat::Table => println!("{} requested", action),
+    }
+}
+
+fn format_status(status: &Status) -> String {
+    let last = status.last_deployment.as_ref();
+    let rows = vec![
+        vec!["container".to_string(), status.container.clone()],
+        vec!["image".to_string(), status.image.clone()],
+        vec!["running".to_string(), status.running.to_string()],
+        vec![
+            "health".to_string(),
+            status.health.clone().unwrap_or_else(|| "-".to_string()),
+        ],
+        vec![
+            "last checked".to_string(),
+            status
+                .last_checked
+                .map_or_else(|| "-".to_string(), |t| t.to_rfc3339()),
+        ],
+        vec![
+            "last deployment".to_string(),
+            last.map_or_else(|| "-".to_string(), |d| d.id.to_string()),
+        ],
+        vec![
+            "outcome".to_string(),
+            last.map_or_else(|| "-".to_string(), |d| format!("{:?}", d.outcome)),
+        ],
+        vec![
+            "digest".to_string(),
+            last.and_then(|d| d.image_digest.clone())
+                .unwrap_or_else(|| "-".to_string()),
+        ],
+        vec![
+            "container id".to_string(),
+            if status.replicas.is_empty() {
+                "-".to_string()
+            } else {
+                status
+                    .replicas
+                    .iter()
+                    .map(|c| c.id.clone())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            },
+        ],
+        vec![
+            "config changed".to_string(),
+            status.config_changed.to_string(),
+        ],
+        vec![
+            "orphans".to_string(),
+            if status.orphans.is_empty() {
+                "-".to_string()
+            } else {
+                status
+                    .orphans
+                    .iter()
+                    .map(|c| format!("{} ({})", c.name, c.service))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            },
+        ],
+    ];
+
+    format_table(None, &rows)
+}
+
+fn format_history(history: &[Deployment]) -> String {
+    let headers = ["ID", "TRIGGER", "OUTCOME", "STARTED", "DURATION", "IMAGE"];
+    let rows: Vec<Vec<String>> = history
+        .iter()
+        .map(|d| {
+            vec![
+                d.id.to_string(),
+                format!("{:?}", d.trigger),
+                format!("{:?}", d.outcome),
+                d.started_at.to_rfc3339(),
+                d.finished_at.map_or_else(
+                    || "-".to_string(),
+                    |t| format!("{}s", (t - d.started_at).num_seconds()),
+                ),
+                d.image.clone(),
+            ]
+        })
+        .collect();
+
+    format_table(Some(&headers), &rows)
+}
+
+fn format_details(details: &DeploymentDetails) -> String {
+    let mut out = format_history(std::slice::from_ref(&details.deployment));
+    if let Some(error) = &details.deployment.error {
+        out.push_str(&format!("\nError: {}\n", error));
+    }
+    out.push('\n');
+    for line in &details.log {
+        out.push_str(&format!(
+            "{} {:5} {}\n",
+            line.time.to_rfc3339(),
+            line.level,
+            line.message
+        ));
+    }
+    out
+}
+
+/// Lay out rows in left-aligned columns separated by two spaces
+fn format_table(headers: Option<&[&str]>, rows: &[Vec<String>]) -> String {
+    let header_row: Option<Vec<String>> =
+        headers.map(|h| h.iter().map(|s| (*s).to_string()).collect());
+    let all_rows: Vec<&Vec<String>> = header_row.iter().chain(rows.iter()).collect();
+
+    let ncols = all_rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..ncols)
+        .map(|i| {
+            all_rows
+                .iter()
+                .filter_map(|r| r.get(i))
+                .map(|s| s.len())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let mut out = String::new();
+    for row in all_rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output_format() {
+        assert_eq!("json".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert_eq!(
+            "table".parse::<OutputFormat>().unwrap(),
+            OutputFormat::Table
+        );
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_format_table() {
+        let rows = vec![
+            vec!["a".to_string(), "first".to_string()],
+            vec!["longer".to_string(), "second".to_string()],
+        ];
+
+        let table = format_table(Some(&["KEY", "VALUE"]), &rows);
+
+        assert_eq!(table, "KEY     VALUE\na       first\nlonger  second\n");
+    }
+}
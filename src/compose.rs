@@ -0,0 +1,868 @@
+//! docker-compose service import
+//!
+//! Reads one service out of a compose file and maps the keys the daemon understands onto its own
+//! container model. Anything set in `[container]` or `[image]` takes precedence over the compose
+//! file, which only fills in what the config leaves unset. Other compose keys are logged and
+//! ignored.
+
+use crate::config::{
+    ContainerConfig, HealthcheckConfig, ImageConfig, MountConfig, MountType, NetworkConfig,
+    PortConfig, PortRange, Protocol, SelinuxLabel,
+};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Compose keys that are mapped onto the container
+const SUPPORTED_KEYS: &[&str] = &[
+    "image",
+    "container_name",
+    "command",
+    "ports",
+    "volumes",
+    "environment",
+    "networks",
+    "healthcheck",
+    "labels",
+];
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct ComposeConfig {
+    /// Path to the compose file, relative to the config file
+    pub(crate) file: String,
+    /// Service to import, which may be left out if the file only has one
+    pub(crate) service: Option<String>,
+}
+
+/// A compose service translated into the daemon's terms
+#[derive(Debug, Default)]
+pub(crate) struct Imported {
+    pub(crate) name: String,
+    pub(crate) image: Option<ImageConfig>,
+    pub(crate) command: Vec<String>,
+    pub(crate) ports: Vec<PortConfig>,
+    pub(crate) mounts: Vec<MountConfig>,
+    pub(crate) env: BTreeMap<String, String>,
+    pub(crate) networks: Vec<NetworkConfig>,
+    pub(crate) healthcheck: Option<HealthcheckConfig>,
+    pub(crate) labels: BTreeMap<String, String>,
+    /// The service's definition as read, so config hashes change along with it
+    pub(crate) source: String,
+    /// Keys and values that could not be mapped
+    pub(crate) warnings: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ComposeFile {
+    services: BTreeMap<String, serde_yaml::Value>,
+    #[serde(default)]
+    networks: BTreeMap<String, Option<TopNetwork>>,
+}
+
+#[derive(Deserialize, Default)]
+struct TopNetwork {
+    name: Option<String>,
+    driver: Option<String>,
+    #[serde(default)]
+    external: bool,
+    ipam: Option<Ipam>,
+}
+
+#[derive(Deserialize)]
+struct Ipam {
+    #[serde(default)]
+    config: Vec<IpamConfig>,
+}
+
+#[derive(Deserialize)]
+struct IpamConfig {
+    subnet: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Service {
+    image: Option<String>,
+    container_name: Option<String>,
+    command: Option<StringOrList>,
+    #[serde(default)]
+    ports: Vec<PortSpec>,
+    #[serde(default)]
+    volumes: Vec<VolumeSpec>,
+    environment: Option<MapOrList>,
+    networks: Option<ServiceNetworks>,
+    healthcheck: Option<Healthcheck>,
+    labels: Option<MapOrList>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOrList {
+    String(String),
+    List(Vec<String>),
+}
+
+/// `environment` and `labels` are either a map or a list of `KEY=value` strings
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MapOrList {
+    Map(BTreeMap<String, Option<Scalar>>),
+    List(Vec<String>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Scalar {
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl Scalar {
+    fn into_string(self) -> String {
+        match self {
+            Scalar::String(s) => s,
+            Scalar::Int(n) => n.to_string(),
+            Scalar::Float(n) => n.to_string(),
+            Scalar::Bool(b) => b.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortSpec {
+    Short(Scalar),
+    Long {
+        target: u16,
+        published: Option<Scalar>,
+        protocol: Option<String>,
+        host_ip: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum VolumeSpec {
+    Short(String),
+    Long {
+        #[serde(rename = "type")]
+        kind: Option<String>,
+        source: Option<String>,
+        target: String,
+        #[serde(default)]
+        read_only: bool,
+        tmpfs: Option<TmpfsOptions>,
+    },
+}
+
+#[derive(Deserialize)]
+struct TmpfsOptions {
+    size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ServiceNetworks {
+    List(Vec<String>),
+    Map(BTreeMap<String, Option<ServiceNetwork>>),
+}
+
+#[derive(Deserialize, Default)]
+struct ServiceNetwork {
+    #[serde(default)]
+    aliases: Vec<String>,
+    ipv4_address: Option<String>,
+    ipv6_address: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Healthcheck {
+    test: Option<StringOrList>,
+    interval: Option<String>,
+    timeout: Option<String>,
+    retries: Option<u64>,
+    start_period: Option<String>,
+    #[serde(default)]
+    disable: bool,
+}
+
+/// Read `service` from the compose file at `path`
+pub(crate) fn load(path: &Path, service: Option<&str>) -> Result<Imported> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading compose file {}", path.display()))?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("/"));
+    let imported = parse(&text, service, base_dir)
+        .with_context(|| format!("importing compose file {}", path.display()))?;
+
+    for warning in &imported.warnings {
+        log::warn!("{}: {}", path.display(), warning);
+    }
+    Ok(imported)
+}
+
+fn parse(text: &str, service: Option<&str>, base_dir: &Path) -> Result<Imported> {
+    let mut document: serde_yaml::Value = serde_yaml::from_str(text)?;
+    let mut warnings = Vec::new();
+    interpolate_value(
+        &mut document,
+        &|name| std::env::var(name).ok(),
+        &mut warnings,
+    )?;
+    let file: ComposeFile = serde_yaml::from_value(document)?;
+    let name = match service {
+        Some(name) => name.to_string(),
+        None if file.services.len() == 1 => file.services.keys().next().unwrap().clone(),
+        None => anyhow::bail!(
+            "compose.service must be one of: {}",
+            file.services.keys().cloned().collect::<Vec<_>>().join(", ")
+        ),
+    };
+    let value = file
+        .services
+        .get(&name)
+        .with_context(|| format!("no service {} in compose file", name))?;
+
+    let mut imported = Imported {
+        source: serde_yaml::to_string(value)?,
+        warnings,
+        ..Default::default()
+    };
+    if let serde_yaml::Value::Mapping(keys) = value {
+        for key in keys.iter().filter_map(|(key, _)| key.as_str()) {
+            if !SUPPORTED_KEYS.contains(&key) {
+                imported
+                    .warnings
+                    .push(format!("ignoring unsupported key `{}`", key));
+            }
+        }
+    }
+
+    let service: Service = serde_yaml::from_value(value.clone())
+        .with_context(|| format!("parsing service {}", name))?;
+    imported.name = service.container_name.unwrap_or(name);
+    if let Some(image) = service.image {
+        imported.image = Some(image_config(&image)?);
+    }
+    imported.command = match service.command {
+        Some(StringOrList::String(command)) => {
+            shell_words::split(&command).context("splitting command")?
+        }
+        Some(StringOrList::List(command)) => command,
+        None => Vec::new(),
+    };
+    for spec in service.ports {
+        match port_config(spec)? {
+            Some(port) => imported.ports.push(port),
+            None => imported
+                .warnings
+                .push("ignoring a port without a published host port".to_string()),
+        }
+    }
+    for spec in service.volumes {
+        match mount_config(spec, base_dir)? {
+            Some(mount) => imported.mounts.push(mount),
+            None => imported
+                .warnings
+                .push("ignoring an anonymous volume".to_string()),
+        }
+    }
+    imported.env = key_values(service.environment, "environment")?;
+    imported.labels = key_values(service.labels, "labels")?;
+    imported.networks = networks(service.networks, &file.networks);
+    imported.healthcheck = match service.healthcheck {
+        Some(healthcheck) => healthcheck_config(healthcheck)?,
+        None => None,
+    };
+
+    Ok(imported)
+}
+
+impl Imported {
+    /// Fill in whatever the config leaves unset
+    pub(crate) fn apply_to(self, container: &mut ContainerConfig, image: &mut ImageConfig) {
+        if container.name.is_empty() {
+            container.name = self.name;
+        }
+        if image.name.is_empty() {
+            if let Some(imported) = self.image {
+                *image = imported;
+            }
+        }
+        if container.command.is_empty() {
+            container.command = self.command;
+        }
+        if container.ports.is_empty() {
+            container.ports = self.ports;
+        }
+        if container.mounts.is_empty() {
+            container.mounts = self.mounts;
+        }
+        if container.networks.is_empty() && container.network_mode.is_none() {
+            container.networks = self.networks;
+        }
+        if container.healthcheck.is_none() {
+            container.healthcheck = self.healthcheck;
+        }
+        for (key, value) in self.env {
+            container.env.entry(key).or_insert(value);
+        }
+        for (key, value) in self.labels {
+            container.labels.entry(key).or_insert(value);
+        }
+    }
+}
+
+/// Substitute variables in every string in the file, keys aside, as compose does
+fn interpolate_value(
+    value: &mut serde_yaml::Value,
+    lookup: &dyn Fn(&str) -> Option<String>,
+    warnings: &mut Vec<String>,
+) -> Result<()> {
+    match value {
+        serde_yaml::Value::String(text) => *text = interpolate(text, lookup, warnings)?,
+        serde_yaml::Value::Sequence(values) => {
+            for value in values {
+                interpolate_value(value, lookup, warnings)?;
+            }
+        }
+        serde_yaml::Value::Mapping(mapping) => {
+            for (_, value) in mapping.iter_mut() {
+                interpolate_value(value, lookup, warnings)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Substitute `$VAR` and `${VAR}` in `text`, with compose's `${VAR:-default}`, `${VAR-default}`,
+/// `${VAR:?error}` and `${VAR?error}` forms, and `$$` for a literal `$`
+fn interpolate(
+    text: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+    warnings: &mut Vec<String>,
+) -> Result<String> {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let expr = if rest.starts_with('$') {
+            result.push('$');
+            rest = &rest[1..];
+            continue;
+        } else if rest.starts_with('{') {
+            let end = rest
+                .find('}')
+                .with_context(|| format!("unterminated variable in `{}`", text))?;
+            let expr = &rest[1..end];
+            rest = &rest[end + 1..];
+            expr
+        } else {
+            let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            let expr = &rest[..end];
+            rest = &rest[end..];
+            expr
+        };
+
+        let name_end = expr.find(|c| !is_name_char(c)).unwrap_or(expr.len());
+        let (name, modifier) = expr.split_at(name_end);
+        if name.is_empty() {
+            anyhow::bail!("invalid variable in `{}`", text);
+        }
+        let value = lookup(name);
+        let non_empty = value.clone().filter(|v| !v.is_empty());
+        let substituted = if modifier.is_empty() {
+            value.unwrap_or_else(|| {
+                warnings.push(format!(
+                    "variable {} is not set, using an empty string",
+                    name
+                ));
+                String::new()
+            })
+        } else if let Some(default) = modifier.strip_prefix(":-") {
+            non_empty.unwrap_or_else(|| default.to_string())
+        } else if let Some(default) = modifier.strip_prefix('-') {
+            value.unwrap_or_else(|| default.to_string())
+        } else if let Some(error) = modifier.strip_prefix(":?") {
+            non_empty.with_context(|| format!("variable {} is required: {}", name, error))?
+        } else if let Some(error) = modifier.strip_prefix('?') {
+            value.with_context(|| format!("variable {} is required: {}", name, error))?
+        } else {
+            anyhow::bail!("invalid variable `${{{}}}` in `{}`", expr, text);
+        };
+        result.push_str(&substituted);
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+/// Split `name[:tag]`, where a registry port is not mistaken for a tag
+fn image_config(image: &str) -> Result<ImageConfig> {
+    if image.contains('@') {
+        anyhow::bail!("image digests are not supported, use a tag in {}", image);
+    }
+    let last_segment = image.rfind('/').map_or(0, |i| i + 1);
+    let (name, tag) = match image[last_segment..].rfind(':') {
+        Some(i) => {
+            let i = last_segment + i;
+            (&image[..i], &image[i + 1..])
+        }
+        None => (image, "latest"),
+    };
+    Ok(ImageConfig {
+        name: name.to_string(),
+        tag: tag.to_string(),
+    })
+}
+
+/// `None` for a port docker would publish on a random host port, which the daemon has no use for
+fn port_config(spec: PortSpec) -> Result<Option<PortConfig>> {
+    let (host_ip, host, target, protocol) = match spec {
+        PortSpec::Short(spec) => {
+            let spec = spec.into_string();
+            let (mapping, protocol) = match spec.split_once('/') {
+                Some((mapping, protocol)) => (mapping, Some(protocol.to_string())),
+                None => (spec.as_str(), None),
+            };
+            // An IPv6 host address is written in brackets, e.g. `[::1]:8080:80`
+            let (host_ip, rest) = match mapping.strip_prefix('[') {
+                Some(rest) => {
+                    let (ip, rest) = rest
+                        .split_once("]:")
+                        .with_context(|| format!("parsing port {}", spec))?;
+                    (Some(ip.to_string()), rest)
+                }
+                None => (None, mapping),
+            };
+            let parts: Vec<&str> = rest.split(':').collect();
+            match (host_ip, parts.as_slice()) {
+                (None, [target]) => (None, None, target.to_string(), protocol),
+                (host_ip, [host, target]) => (
+                    host_ip,
+                    Some(host.to_string()),
+                    target.to_string(),
+                    protocol,
+                ),
+                (None, [ip, host, target]) => (
+                    Some(ip.to_string()),
+                    Some(host.to_string()),
+                    target.to_string(),
+                    protocol,
+                ),
+                _ => anyhow::bail!("cannot parse port {}", spec),
+            }
+        }
+        PortSpec::Long {
+            target,
+            published,
+            protocol,
+            host_ip,
+        } => (
+            host_ip,
+            published.map(Scalar::into_string),
+            target.to_string(),
+            protocol,
+        ),
+    };
+
+    let host = match host.filter(|host| !host.is_empty()) {
+        Some(host) => host,
+        None => return Ok(None),
+    };
+    let protocol = match protocol.as_deref() {
+        None | Some("tcp") => Protocol::Tcp,
+        Some("udp") => Protocol::Udp,
+        Some("sctp") => Protocol::Sctp,
+        Some(other) => anyhow::bail!("unknown protocol {}", other),
+    };
+    let port = PortConfig {
+        host: host.parse::<PortRange>()?,
+        target: target.parse::<PortRange>()?,
+        protocol,
+        host_ip: host_ip.filter(|ip| !ip.is_empty()),
+    };
+    Ok(Some(port))
+}
+
+/// `None` for an anonymous volume, which would be thrown away with each container
+fn mount_config(spec: VolumeSpec, base_dir: &Path) -> Result<Option<MountConfig>> {
+    let mut mount = MountConfig {
+        kind: MountType::Bind,
+        host: None,
+        target: String::new(),
+        read_only: false,
+        propagation: None,
+        selinux_label: None,
+        tmpfs_size: None,
+        tmpfs_mode: None,
+    };
+
+    match spec {
+        VolumeSpec::Short(spec) => {
+            let parts: Vec<&str> = spec.split(':').collect();
+            let (source, target, options) = match parts.as_slice() {
+                [_] => return Ok(None),
+                [source, target] => (*source, *target, ""),
+                [source, target, options] => (*source, *target, *options),
+                _ => anyhow::bail!("cannot parse volume {}", spec),
+            };
+            mount.host = Some(source.to_string());
+            mount.target = target.to_string();
+            for option in options.split(',').filter(|o| !o.is_empty()) {
+                match option {
+                    "ro" => mount.read_only = true,
+                    "rw" => {}
+                    "z" => mount.selinux_label = Some(SelinuxLabel::Shared),
+                    "Z" => mount.selinux_label = Some(SelinuxLabel::Private),
+                    other => anyhow::bail!("unsupported volume option {} in {}", other, spec),
+                }
+            }
+            if !is_path(source) {
+                mount.kind = MountType::Volume;
+            }
+        }
+        VolumeSpec::Long {
+            kind,
+            source,
+            target,
+            read_only,
+            tmpfs,
+        } => {
+            mount.kind = match kind.as_deref() {
+                None | Some("volume") => MountType::Volume,
+                Some("bind") => MountType::Bind,
+                Some("tmpfs") => MountType::Tmpfs,
+                Some(other) => anyhow::bail!("unsupported volume type {}", other),
+            };
+            if mount.kind != MountType::Tmpfs && source.is_none() {
+                return Ok(None);
+            }
+            mount.host = source;
+            mount.target = target;
+            mount.read_only = read_only;
+            mount.tmpfs_size = tmpfs.and_then(|tmpfs| tmpfs.size);
+        }
+    }
+
+    // Compose paths are relative to the compose file
+    if mount.kind == MountType::Bind {
+        if let Some(host) = &mut mount.host {
+            if let Some(rest) = host.strip_prefix("~/") {
+                let home = std::env::var("HOME").context("expanding ~ in a volume")?;
+                *host = Path::new(&home).join(rest).to_string_lossy().into_owned();
+            }
+            let relative = host.strip_prefix("./").unwrap_or(host);
+            *host = base_dir.join(relative).to_string_lossy().into_owned();
+        }
+    }
+    Ok(Some(mount))
+}
+
+fn is_path(source: &str) -> bool {
+    source.starts_with('/') || source.starts_with('.') || source.starts_with('~')
+}
+
+fn key_values(values: Option<MapOrList>, what: &str) -> Result<BTreeMap<String, String>> {
+    let mut result = BTreeMap::new();
+    match values {
+        Some(MapOrList::Map(map)) => {
+            for (key, value) in map {
+                // Compose reads a key without a value from its own environment
+                let value = match value {
+                    Some(value) => Some(value.into_string()),
+                    None => std::env::var(&key).ok(),
+                };
+                if let Some(value) = value {
+                    result.insert(key, value);
+                }
+            }
+        }
+        Some(MapOrList::List(list)) => {
+            for item in list {
+                match item.split_once('=') {
+                    Some((key, value)) => {
+                        result.insert(key.to_string(), value.to_string());
+                    }
+                    None if what == "environment" => {
+                        if let Ok(value) = std::env::var(&item) {
+                            result.insert(item, value);
+                        }
+                    }
+                    None => {
+                        result.insert(item, String::new());
+                    }
+                }
+            }
+        }
+        None => {}
+    }
+    Ok(result)
+}
+
+fn networks(
+    service: Option<ServiceNetworks>,
+    top_level: &BTreeMap<String, Option<TopNetwork>>,
+) -> Vec<NetworkConfig> {
+    let service: Vec<(String, ServiceNetwork)> = match service {
+        Some(ServiceNetworks::List(names)) => names
+            .into_iter()
+            .map(|name| (name, ServiceNetwork::default()))
+            .collect(),
+        Some(ServiceNetworks::Map(map)) => map
+            .into_iter()
+            .map(|(name, network)| (name, network.unwrap_or_default()))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    service
+        .into_iter()
+        .map(|(key, network)| {
+            let top = top_level.get(&key).and_then(Option::as_ref);
+            let subnet = top
+                .and_then(|top| top.ipam.as_ref())
+                .and_then(|ipam| ipam.config.iter().find_map(|c| c.subnet.clone()));
+            NetworkConfig {
+                name: top.and_then(|top| top.name.clone()).unwrap_or(key),
+                aliases: network.aliases,
+                ipv4_address: network.ipv4_address,
+                ipv6_address: network.ipv6_address,
+                create: !top.is_some_and(|top| top.external),
+                driver: top.and_then(|top| top.driver.clone()),
+                subnet,
+            }
+        })
+        .collect()
+}
+
+fn healthcheck_config(healthcheck: Healthcheck) -> Result<Option<HealthcheckConfig>> {
+    let test = if healthcheck.disable {
+        vec!["NONE".to_string()]
+    } else {
+        match healthcheck.test {
+            Some(StringOrList::String(command)) => vec!["CMD-SHELL".to_string(), command],
+            Some(StringOrList::List(test)) => test,
+            // Only the timings are overridden, which the daemon cannot express
+            None => return Ok(None),
+        }
+    };
+    let seconds = |duration: Option<String>| duration.as_deref().map(parse_duration).transpose();
+
+    Ok(Some(HealthcheckConfig {
+        test,
+        interval: seconds(healthcheck.interval)?,
+        timeout: seconds(healthcheck.timeout)?,
+        retries: healthcheck.retries,
+        start_period: seconds(healthcheck.start_period)?,
+    }))
+}
+
+/// Compose's durations, e.g. `1m30s` or `500ms`, rounded up to whole seconds
+fn parse_duration(text: &str) -> Result<u64> {
+    let mut nanos = 0f64;
+    let mut rest = text.trim();
+    if rest.is_empty() {
+        anyhow::bail!("empty duration");
+    }
+    while !rest.is_empty() {
+        let number_end = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .with_context(|| format!("duration {} is missing a unit", text))?;
+        let unit_end = rest[number_end..]
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .map_or(rest.len(), |i| number_end + i);
+        let number: f64 = rest[..number_end]
+            .parse()
+            .with_context(|| format!("parsing duration {}", text))?;
+        let scale = match &rest[number_end..unit_end] {
+            "h" => 3600e9,
+            "m" => 60e9,
+            "s" => 1e9,
+            "ms" => 1e6,
+            "us" => 1e3,
+            "ns" => 1.0,
+            unit => anyhow::bail!("unknown unit {} in duration {}", unit, text),
+        };
+        nanos += number * scale;
+        rest = &rest[unit_end..];
+    }
+    Ok((nanos / 1e9).ceil() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPOSE: &str = r#"
+version: "3.7"
+services:
+  web:
+    image: registry.example.com:5000/foobar:1.2
+    build: .
+    command: gunicorn -b "0.0.0.0:80" app:app
+    ports:
+      - "5020:80"
+      - "127.0.0.1:6000-6001:6000-6001/udp"
+      - "9000"
+      - target: 443
+        published: 5443
+    volumes:
+      - ./data:/data:ro
+      - cache:/cache
+      - /anonymous
+      - type: tmpfs
+        target: /tmp
+        tmpfs:
+          size: 1024
+    environment:
+      DEBUG: "false"
+      WORKERS: 4
+    networks:
+      backend:
+        aliases: [app]
+      proxy:
+    healthcheck:
+      test: curl -f http://localhost/health
+      interval: 1m30s
+      retries: 3
+    labels:
+      - com.example.team=web
+  worker:
+    image: foobar
+networks:
+  backend:
+    driver: bridge
+    ipam:
+      config:
+        - subnet: 172.28.0.0/16
+  proxy:
+    external: true
+    name: traefik
+"#;
+
+    #[test]
+    fn test_parse_service() {
+        let imported = parse(COMPOSE, Some("web"), Path::new("/srv/foobar")).unwrap();
+
+        assert_eq!(imported.name, "web");
+        let image = imported.image.as_ref().unwrap();
+        assert_eq!(image.name, "registry.example.com:5000/foobar");
+        assert_eq!(image.tag, "1.2");
+        assert_eq!(
+            imported.command,
+            vec!["gunicorn", "-b", "0.0.0.0:80", "app:app"]
+        );
+
+        let ports: Vec<String> = imported.ports.iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            ports,
+            vec![
+                "5020:80/tcp",
+                "127.0.0.1:6000-6001:6000-6001/udp",
+                "5443:443/tcp"
+            ]
+        );
+
+        assert_eq!(imported.mounts.len(), 3);
+        assert_eq!(imported.mounts[0].host.as_deref(), Some("/srv/foobar/data"));
+        assert!(imported.mounts[0].read_only);
+        assert_eq!(imported.mounts[1].kind, MountType::Volume);
+        assert_eq!(imported.mounts[2].kind, MountType::Tmpfs);
+        assert_eq!(imported.mounts[2].tmpfs_size, Some(1024));
+
+        assert_eq!(imported.env["WORKERS"], "4");
+        assert_eq!(imported.labels["com.example.team"], "web");
+
+        assert_eq!(imported.networks.len(), 2);
+        assert_eq!(imported.networks[0].name, "backend");
+        assert_eq!(imported.networks[0].aliases, vec!["app"]);
+        assert_eq!(
+            imported.networks[0].subnet.as_deref(),
+            Some("172.28.0.0/16")
+        );
+        assert!(imported.networks[0].create);
+        assert_eq!(imported.networks[1].name, "traefik");
+        assert!(!imported.networks[1].create);
+
+        let healthcheck = imported.healthcheck.as_ref().unwrap();
+        assert_eq!(healthcheck.test[0], "CMD-SHELL");
+        assert_eq!(healthcheck.interval, Some(90));
+        assert_eq!(healthcheck.retries, Some(3));
+
+        assert_eq!(imported.warnings.len(), 3, "{:?}", imported.warnings);
+        assert!(imported.warnings[0].contains("`build`"));
+    }
+
+    #[test]
+    fn test_choose_service() {
+        assert!(parse(COMPOSE, None, Path::new("/")).is_err());
+        assert!(parse(COMPOSE, Some("db"), Path::new("/")).is_err());
+
+        let single = "services:\n  app:\n    image: foobar\n";
+        let imported = parse(single, None, Path::new("/")).unwrap();
+        assert_eq!(imported.name, "app");
+        assert_eq!(imported.image.unwrap().tag, "latest");
+    }
+
+    #[test]
+    fn test_apply_to() {
+        let imported = parse(COMPOSE, Some("web"), Path::new("/srv/foobar")).unwrap();
+        let mut container = ContainerConfig {
+            name: "foobar".to_string(),
+            command: vec!["./run".to_string()],
+            env: vec![("DEBUG".to_string(), "true".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let mut image = ImageConfig::default();
+
+        imported.apply_to(&mut container, &mut image);
+
+        assert_eq!(container.name, "foobar");
+        assert_eq!(container.command, vec!["./run"]);
+        assert_eq!(container.env["DEBUG"], "true");
+        assert_eq!(container.env["WORKERS"], "4");
+        assert_eq!(container.ports.len(), 3);
+        assert_eq!(image.tag, "1.2");
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("10s").unwrap(), 10);
+        assert_eq!(parse_duration("1m30s").unwrap(), 90);
+        assert_eq!(parse_duration("1h").unwrap(), 3600);
+        assert_eq!(parse_duration("500ms").unwrap(), 1);
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("10d").is_err());
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let lookup = |name: &str| match name {
+            "TAG" => Some("1.2".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let mut warnings = Vec::new();
+        let mut run = |text: &str| interpolate(text, &lookup, &mut warnings);
+
+        assert_eq!(run("foobar:${TAG}").unwrap(), "foobar:1.2");
+        assert_eq!(run("foobar:$TAG-slim").unwrap(), "foobar:1.2-slim");
+        assert_eq!(run("${EMPTY:-latest}").unwrap(), "latest");
+        assert_eq!(run("${EMPTY-latest}").unwrap(), "");
+        assert_eq!(run("${MISSING-latest}").unwrap(), "latest");
+        assert_eq!(run("cost: $$5").unwrap(), "cost: $5");
+        assert!(run("${MISSING:?set a tag}").is_err());
+        assert!(run("${TAG").is_err());
+        assert_eq!(run("${MISSING}").unwrap(), "");
+        assert_eq!(warnings.len(), 1);
+    }
+}
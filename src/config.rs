@@ -1,23 +1,209 @@
-use anyhow::Result;
-use serde::Deserialize;
+use crate::compose::ComposeConfig;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
 
 #[derive(Deserialize, Debug, Default)]
 pub(crate) struct DockerDeployConfig {
+    /// Read for compatibility with existing configs, not used yet
+    #[allow(dead_code)]
     pub(crate) api_version: String,
     pub(crate) validation_key: Option<String>,
+    /// Remove containers labelled for another service, e.g. ones left behind after a rename. Only
+    /// safe when no other daemon manages containers on the same host.
+    #[serde(default)]
+    pub(crate) remove_orphans: bool,
     pub(crate) server: Option<ServerConfig>,
+    /// Tokens for the API. Without this section the API is open to anyone who can reach it.
+    pub(crate) auth: Option<AuthConfig>,
+    /// May be left out when a compose file names the image
+    #[serde(default)]
     pub(crate) image: ImageConfig,
+    /// May be left out, or only partly filled in, when importing from a compose file
+    #[serde(default)]
     pub(crate) container: ContainerConfig,
+    pub(crate) compose: Option<ComposeConfig>,
+    /// Build the image from source in place of pulling it
+    pub(crate) build: Option<BuildConfig>,
+    /// Read for compatibility with existing configs, not used yet
+    #[allow(dead_code)]
     pub(crate) branch: BranchConfig,
     pub(crate) heartbeat: HeartbeatConfig,
+    pub(crate) notifications: Option<NotificationsConfig>,
+    #[serde(default)]
+    pub(crate) hooks: HooksConfig,
+    pub(crate) readiness: Option<ReadinessConfig>,
+    pub(crate) canary: Option<CanaryConfig>,
+    #[serde(default)]
+    pub(crate) rollout: RolloutConfig,
+    #[serde(default)]
+    pub(crate) sidecars: Vec<SidecarConfig>,
 }
 
 impl DockerDeployConfig {
-    pub(crate) fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+    pub(crate) fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
         let text = std::fs::read_to_string(path)?;
-        let config = toml::from_str(&text)?;
+        let mut config: DockerDeployConfig = toml::from_str(&text)?;
+
+        // Relative paths in the config are relative to the config file, not wherever the daemon
+        // happened to be started from
+        let path = path.canonicalize()?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("/"));
+
+        config.container.hash = container_hash(&text)?;
+        if let Some(compose) = &mut config.compose {
+            compose.file = base_dir.join(&compose.file).to_string_lossy().into_owned();
+            let imported =
+                crate::compose::load(Path::new(&compose.file), compose.service.as_deref())?;
+            config.container.hash = hash_parts(&[&config.container.hash, &imported.source]);
+            imported.apply_to(&mut config.container, &mut config.image);
+        }
+        config.validate()?;
+        for (sidecar, hash) in config.sidecars.iter_mut().zip(sidecar_hashes(&text)?) {
+            sidecar.hash = hash;
+        }
+        config.resolve_paths(base_dir)?;
+
         Ok(config)
     }
+
+    /// Checks that cannot be expressed through deserialisation alone
+    fn validate(&self) -> Result<()> {
+        if self.container.name.is_empty() {
+            anyhow::bail!("container.name is required unless a compose file provides it");
+        }
+        if self.image.name.is_empty() || self.image.tag.is_empty() {
+            anyhow::bail!(
+                "image.name and image.tag are required unless a compose file provides them"
+            );
+        }
+        for port in &self.container.ports {
+            port.validate()
+                .with_context(|| format!("invalid port mapping {}", port))?;
+        }
+        self.container
+            .runtime
+            .validate()
+            .context("invalid container settings")?;
+        if let Some(readiness) = &self.readiness {
+            readiness.validate().context("invalid readiness probe")?;
+        }
+        if self.container.replicas == 0 {
+            anyhow::bail!("container.replicas must be at least 1");
+        }
+        if self.container.replicas > 1
+            && self
+                .container
+                .networks
+                .iter()
+                .any(|n| n.ipv4_address.is_some() || n.ipv6_address.is_some())
+        {
+            anyhow::bail!("replicas cannot share a static ipv4_address or ipv6_address");
+        }
+        if self.rollout.batch_size == 0 {
+            anyhow::bail!("rollout.batch_size must be at least 1");
+        }
+        if self.heartbeat.sleep_time == 0 {
+            anyhow::bail!("heartbeat.sleep_time must be at least 1");
+        }
+        crate::replicas::plan(&self.container).context("invalid replica ports")?;
+        for sidecar in &self.sidecars {
+            sidecar
+                .validate()
+                .with_context(|| format!("invalid sidecar {}", sidecar.name))?;
+        }
+        crate::sidecars::order(&self.sidecars).context("invalid sidecars")?;
+        if let Some(canary) = &self.canary {
+            canary.validate().context("invalid canary settings")?;
+        }
+        for hook in self.hooks.pre_deploy.iter().chain(&self.hooks.post_deploy) {
+            hook.validate()
+                .with_context(|| format!("invalid hook {}", hook.name))?;
+        }
+        if let Some(pre_stop) = &self.container.stop.pre_stop {
+            pre_stop.validate().context("invalid pre_stop hook")?;
+        }
+        if let Some(healthcheck) = &self.container.healthcheck {
+            healthcheck.validate().context("invalid healthcheck")?;
+        }
+        if let Some(key) = self
+            .container
+            .labels
+            .keys()
+            .find(|key| key.starts_with(crate::labels::PREFIX))
+        {
+            anyhow::bail!(
+                "container label {} uses the reserved prefix {}",
+                key,
+                crate::labels::PREFIX
+            );
+        }
+        if self.container.network_mode.is_some() && !self.container.networks.is_empty() {
+            anyhow::bail!("container.network_mode cannot be combined with container.networks");
+        }
+        for network in &self.container.networks {
+            network
+                .validate()
+                .with_context(|| format!("invalid network {}", network.name))?;
+        }
+        for mount in &self.container.mounts {
+            mount
+                .validate()
+                .with_context(|| format!("invalid mount for {}", mount.target))?;
+        }
+        if let Some(auth) = &self.auth {
+            auth.validate().context("invalid auth settings")?;
+            if self.validation_key.is_none() {
+                anyhow::bail!(
+                    "validation_key is required with [auth], or /webhook would let anyone deploy"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_paths(&mut self, base_dir: &Path) -> Result<()> {
+        if let Some(build) = &mut self.build {
+            if build.is_local() {
+                build.repo = base_dir.join(&build.repo).to_string_lossy().into_owned();
+            }
+        }
+        let sidecar_mounts = self.sidecars.iter_mut().flat_map(|s| &mut s.mounts);
+        for mount in self.container.mounts.iter_mut().chain(sidecar_mounts) {
+            if mount.kind == MountType::Bind {
+                if let Some(host) = &mut mount.host {
+                    // Older configs used `$PWD` for paths relative to where the daemon was
+                    // started, which still works the same
+                    if host.contains("$PWD") {
+                        let cwd = std::env::current_dir().context("expanding $PWD")?;
+                        *host = host.replace("$PWD", &cwd.to_string_lossy());
+                    }
+                    *host = base_dir.join(&host).to_string_lossy().into_owned();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Address the web server listens on, which is also where the client subcommands connect to
+    pub(crate) fn listen_address(&self) -> Result<SocketAddr> {
+        let ip_address: IpAddr = self
+            .server
+            .as_ref()
+            .and_then(|s| s.ip_address.as_deref())
+            .unwrap_or("127.0.0.1")
+            .parse()
+            .context("parsing IP address")?;
+        let port = self.server.as_ref().and_then(|s| s.port).unwrap_or(8080);
+
+        Ok(SocketAddr::new(ip_address, port))
+    }
 }
 
 #[derive(Deserialize, Debug, Default)]
@@ -26,42 +212,1011 @@ pub(crate) struct ServerConfig {
     pub(crate) port: Option<u16>,
 }
 
+#[derive(Deserialize, Debug, Clone, Default)]
+pub(crate) struct AuthConfig {
+    #[serde(default)]
+    pub(crate) tokens: Vec<TokenConfig>,
+}
+
+impl AuthConfig {
+    fn validate(&self) -> Result<()> {
+        if self.tokens.is_empty() {
+            anyhow::bail!("at least one token is required");
+        }
+        let mut names = std::collections::HashSet::new();
+        for token in &self.tokens {
+            if !names.insert(token.name.as_str()) {
+                anyhow::bail!("token {} is declared twice", token.name);
+            }
+            token
+                .validate()
+                .with_context(|| format!("invalid token {}", token.name))?;
+        }
+        Ok(())
+    }
+}
+
+/// A bearer token for the API, named so the audit log can say who did what
+#[derive(Deserialize, Clone)]
+pub(crate) struct TokenConfig {
+    pub(crate) name: String,
+    pub(crate) token: String,
+    pub(crate) scopes: Vec<Scope>,
+    /// Only allow the token for these services. All services are allowed if left out.
+    pub(crate) services: Option<Vec<String>>,
+}
+
+impl TokenConfig {
+    /// Tokens shorter than this are too easy to guess
+    const MIN_LENGTH: usize = 16;
+
+    fn validate(&self) -> Result<()> {
+        if self.name.is_empty() {
+            anyhow::bail!("name must not be empty");
+        }
+        if self.token.len() < Self::MIN_LENGTH {
+            anyhow::bail!("token must be at least {} characters", Self::MIN_LENGTH);
+        }
+        if self.scopes.is_empty() {
+            anyhow::bail!("at least one scope is required");
+        }
+        Ok(())
+    }
+}
+
+// Keeps the token itself out of logs
+impl fmt::Debug for TokenConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenConfig")
+            .field("name", &self.name)
+            .field("token", &"<redacted>")
+            .field("scopes", &self.scopes)
+            .field("services", &self.services)
+            .finish()
+    }
+}
+
+/// What a token may do. `admin` allows everything.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Scope {
+    /// Status, history, logs, events, metrics and the heartbeat
+    Read,
+    /// `/trigger`
+    Deploy,
+    /// `/rollback`
+    Rollback,
+    /// Everything, including the audit log
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Scope::Read => "read",
+            Scope::Deploy => "deploy",
+            Scope::Rollback => "rollback",
+            Scope::Admin => "admin",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Where to build the image from. The result is tagged with `[image]`'s name and tag.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct BuildConfig {
+    /// Git repository URL, or a path to a local repository relative to the config file
+    pub(crate) repo: String,
+    /// Branch, tag or commit to build
+    #[serde(rename = "ref", default = "BuildConfig::default_ref")]
+    pub(crate) git_ref: String,
+    /// Path to the Dockerfile within the repository
+    #[serde(default = "BuildConfig::default_dockerfile")]
+    pub(crate) dockerfile: String,
+    #[serde(default)]
+    pub(crate) args: BTreeMap<String, String>,
+    /// Stage of a multi-stage Dockerfile to build
+    pub(crate) target: Option<String>,
+    /// Where the fetched repository is kept between builds, defaulting to a directory under the
+    /// system's temporary directory
+    pub(crate) checkout_dir: Option<String>,
+}
+
+impl BuildConfig {
+    fn default_ref() -> String {
+        "HEAD".to_string()
+    }
+
+    fn default_dockerfile() -> String {
+        "Dockerfile".to_string()
+    }
+
+    /// Whether `repo` is a path rather than a URL such as `https://...` or `git@host:repo`
+    pub(crate) fn is_local(&self) -> bool {
+        !self.repo.contains(':')
+    }
+}
+
 #[derive(Deserialize, Debug, Default)]
 pub(crate) struct ImageConfig {
     pub(crate) name: String,
     pub(crate) tag: String,
 }
 
+impl ImageConfig {
+    /// The `name:tag` form docker expects
+    pub(crate) fn reference(&self) -> String {
+        format!("{}:{}", self.name, self.tag)
+    }
+}
+
+/// Hex SHA-1 of the `[container]` table, ignoring formatting and key order
+fn container_hash(text: &str) -> Result<String> {
+    let value: toml::Value = toml::from_str(text)?;
+    table_hash(value.get("container"))
+}
+
+/// Hashes of each `[[sidecars]]` table, in order
+fn sidecar_hashes(text: &str) -> Result<Vec<String>> {
+    let value: toml::Value = toml::from_str(text)?;
+    let sidecars = match value.get("sidecars").and_then(toml::Value::as_array) {
+        Some(sidecars) => sidecars,
+        None => return Ok(Vec::new()),
+    };
+    sidecars
+        .iter()
+        .map(|table| table_hash(Some(table)))
+        .collect()
+}
+
+fn table_hash(table: Option<&toml::Value>) -> Result<String> {
+    let table = table.map(toml::to_string).transpose()?.unwrap_or_default();
+    Ok(hash_parts(&[&table]))
+}
+
+fn hash_parts(parts: &[&str]) -> String {
+    use sha1::{Digest, Sha1};
+
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.input(part.as_bytes());
+    }
+    hex::encode(hasher.result())
+}
+
 #[derive(Deserialize, Debug, Default)]
 pub(crate) struct ContainerConfig {
     pub(crate) name: String,
     pub(crate) command: Vec<String>,
     pub(crate) ports: Vec<PortConfig>,
     pub(crate) mounts: Vec<MountConfig>,
+    /// User-defined networks to attach to. The first one replaces docker's default bridge.
+    #[serde(default)]
+    pub(crate) networks: Vec<NetworkConfig>,
+    /// Docker's network mode, e.g. `host` or `none`, for containers not using `networks`
+    pub(crate) network_mode: Option<String>,
+    #[serde(flatten)]
+    pub(crate) runtime: RuntimeConfig,
+    /// Extra labels for the container, alongside the daemon's own `dockerdeploy.*` labels
+    #[serde(default)]
+    pub(crate) labels: BTreeMap<String, String>,
+    /// Environment variables for the container
+    #[serde(default)]
+    pub(crate) env: BTreeMap<String, String>,
+    /// Replaces the image's own health check
+    pub(crate) healthcheck: Option<HealthcheckConfig>,
+    #[serde(default)]
+    pub(crate) stop: StopConfig,
+    /// How many copies of the container to run, named `name-1` to `name-N` when more than one
+    #[serde(default = "ContainerConfig::default_replicas")]
+    pub(crate) replicas: u16,
+    /// How far each replica's host ports are shifted from the previous replica's
+    #[serde(default = "ContainerConfig::default_replica_port_offset")]
+    pub(crate) replica_port_offset: u16,
+    /// How many lines of a dead container's logs to keep with its crash report
+    #[serde(default = "ContainerConfig::default_crash_log_lines")]
+    pub(crate) crash_log_lines: u64,
+    /// Hash of the `[container]` table, to tell which configuration a container was started with
+    #[serde(skip)]
+    pub(crate) hash: String,
+}
+
+impl ContainerConfig {
+    fn default_crash_log_lines() -> u64 {
+        50
+    }
+
+    fn default_replicas() -> u16 {
+        1
+    }
+
+    fn default_replica_port_offset() -> u16 {
+        1
+    }
+}
+
+/// How deploys replace the replicas of a service
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct RolloutConfig {
+    /// How many replicas are replaced at a time
+    #[serde(default = "RolloutConfig::default_batch_size")]
+    pub(crate) batch_size: u16,
+}
+
+impl RolloutConfig {
+    fn default_batch_size() -> u16 {
+        1
+    }
+}
+
+impl Default for RolloutConfig {
+    fn default() -> Self {
+        RolloutConfig {
+            batch_size: RolloutConfig::default_batch_size(),
+        }
+    }
+}
+
+/// A companion container, such as a cache or a log shipper, started before the service and kept
+/// running across its deploys. It is named `<service>-<name>`.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct SidecarConfig {
+    pub(crate) name: String,
+    pub(crate) image: String,
+    #[serde(default = "SidecarConfig::default_tag")]
+    pub(crate) tag: String,
+    /// Defaults to the image's own command
+    #[serde(default)]
+    pub(crate) command: Vec<String>,
+    #[serde(default)]
+    pub(crate) ports: Vec<PortConfig>,
+    #[serde(default)]
+    pub(crate) mounts: Vec<MountConfig>,
+    /// Defaults to the service's networks, where the sidecar can be reached by its `name`
+    pub(crate) networks: Option<Vec<NetworkConfig>>,
+    #[serde(flatten)]
+    pub(crate) runtime: RuntimeConfig,
+    #[serde(default)]
+    pub(crate) labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub(crate) env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub(crate) stop: StopConfig,
+    /// Other sidecars that must be started before this one
+    #[serde(default)]
+    pub(crate) depends_on: Vec<String>,
+    /// Hash of the sidecar's table, to tell when it needs recreating
+    #[serde(skip)]
+    pub(crate) hash: String,
+}
+
+impl SidecarConfig {
+    fn default_tag() -> String {
+        "latest".to_string()
+    }
+
+    /// The `name:tag` form docker expects
+    pub(crate) fn reference(&self) -> String {
+        format!("{}:{}", self.image, self.tag)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if self.name.is_empty() || !self.name.chars().all(valid_char) {
+            anyhow::bail!("name may only contain letters, digits, `_` and `-`");
+        }
+        // Replicas are named `<service>-<n>`
+        if self.name.chars().all(|c| c.is_ascii_digit()) {
+            anyhow::bail!("name must not be a number");
+        }
+        for port in &self.ports {
+            port.validate()
+                .with_context(|| format!("invalid port mapping {}", port))?;
+        }
+        for mount in &self.mounts {
+            mount
+                .validate()
+                .with_context(|| format!("invalid mount for {}", mount.target))?;
+        }
+        for network in self.networks.iter().flatten() {
+            network
+                .validate()
+                .with_context(|| format!("invalid network {}", network.name))?;
+        }
+        if self.stop.pre_stop.is_some() {
+            anyhow::bail!("pre_stop hooks are not supported for sidecars");
+        }
+        if let Some(key) = self
+            .labels
+            .keys()
+            .find(|key| key.starts_with(crate::labels::PREFIX))
+        {
+            anyhow::bail!(
+                "label {} uses the reserved prefix {}",
+                key,
+                crate::labels::PREFIX
+            );
+        }
+        self.runtime.validate()
+    }
+}
+
+/// A docker health check, whose status is reported by `/status`
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct HealthcheckConfig {
+    /// In docker's form: `["CMD", args...]`, `["CMD-SHELL", command]`, or `["NONE"]` to turn off
+    /// the image's health check
+    pub(crate) test: Vec<String>,
+    /// Seconds between checks
+    pub(crate) interval: Option<u64>,
+    /// Seconds before a check counts as failed
+    pub(crate) timeout: Option<u64>,
+    /// Failures in a row before the container is unhealthy
+    pub(crate) retries: Option<u64>,
+    /// Seconds after starting during which failures are not counted
+    pub(crate) start_period: Option<u64>,
+}
+
+impl HealthcheckConfig {
+    fn validate(&self) -> Result<()> {
+        match self.test.first().map(String::as_str) {
+            Some("NONE") if self.test.len() == 1 => Ok(()),
+            Some("CMD") | Some("CMD-SHELL") if self.test.len() > 1 => Ok(()),
+            _ => anyhow::bail!(
+                "test must be [\"CMD\", args...], [\"CMD-SHELL\", command] or [\"NONE\"]"
+            ),
+        }
+    }
+}
+
+/// How the running container is shut down before it is replaced
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct StopConfig {
+    /// Signal docker sends to stop the container, e.g. `SIGINT`, defaulting to the image's own
+    pub(crate) signal: Option<String>,
+    /// Seconds to wait after the stop signal before the container is killed
+    #[serde(default = "StopConfig::default_timeout")]
+    pub(crate) timeout: u64,
+    pub(crate) pre_stop: Option<PreStopConfig>,
+}
+
+impl StopConfig {
+    fn default_timeout() -> u64 {
+        10
+    }
+}
+
+impl Default for StopConfig {
+    fn default() -> Self {
+        StopConfig {
+            signal: None,
+            timeout: StopConfig::default_timeout(),
+            pre_stop: None,
+        }
+    }
+}
+
+/// Run before the stop signal is sent, e.g. to drain connections. Exactly one of `http` and
+/// `exec` is set.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct PreStopConfig {
+    /// URL to GET
+    pub(crate) http: Option<String>,
+    /// Command to run inside the container
+    pub(crate) exec: Option<Vec<String>>,
+    /// Seconds to wait for the hook before stopping anyway
+    #[serde(default = "StopConfig::default_timeout")]
+    pub(crate) timeout: u64,
+}
+
+impl PreStopConfig {
+    fn validate(&self) -> Result<()> {
+        match (&self.http, &self.exec) {
+            (Some(_), None) => Ok(()),
+            (None, Some(cmd)) if !cmd.is_empty() => Ok(()),
+            (None, Some(_)) => anyhow::bail!("exec needs a command"),
+            _ => anyhow::bail!("exactly one of http and exec must be set"),
+        }
+    }
+}
+
+/// Checked after the new container starts; the deploy only succeeds once it passes. At least one
+/// of `http` and `tcp` is set.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct ReadinessConfig {
+    /// URL to GET
+    pub(crate) http: Option<String>,
+    /// Address to open a TCP connection to, e.g. `127.0.0.1:5020`
+    pub(crate) tcp: Option<String>,
+    /// Status the HTTP probe must return, defaulting to any 2xx
+    pub(crate) expected_status: Option<u16>,
+    /// Seconds to keep probing before the deploy fails
+    #[serde(default = "ReadinessConfig::default_timeout")]
+    pub(crate) timeout: u64,
+    /// Seconds between probes
+    #[serde(default = "ReadinessConfig::default_interval")]
+    pub(crate) interval: u64,
+}
+
+impl ReadinessConfig {
+    fn default_timeout() -> u64 {
+        60
+    }
+
+    fn default_interval() -> u64 {
+        2
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.http.is_none() && self.tcp.is_none() {
+            anyhow::bail!("one of http and tcp must be set");
+        }
+        if self.expected_status.is_some() && self.http.is_none() {
+            anyhow::bail!("expected_status only applies to http probes");
+        }
+        if self.interval == 0 {
+            anyhow::bail!("interval must be at least 1 second");
+        }
+        if let Some(address) = &self.tcp {
+            address
+                .parse::<SocketAddr>()
+                .with_context(|| format!("parsing tcp address `{}`", address))?;
+        }
+        Ok(())
+    }
+}
+
+/// Soak a new image in a canary container next to the running one before promoting it. Only
+/// deploys triggered through the API or a webhook go through a canary.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct CanaryConfig {
+    /// Appended to the container name to name the canary, e.g. `foobar-canary`
+    #[serde(default = "CanaryConfig::default_suffix")]
+    pub(crate) suffix: String,
+    /// The canary's own port mappings, as it cannot share host ports with the running container
+    #[serde(default)]
+    pub(crate) ports: Vec<PortConfig>,
+    /// Seconds the canary must stay healthy before it is promoted
+    pub(crate) duration: u64,
+    /// Seconds between checks on the canary
+    #[serde(default = "CanaryConfig::default_interval")]
+    pub(crate) interval: u64,
+    /// Probe against the canary, waited on like the readiness probe before the soak starts and
+    /// then run at every check
+    pub(crate) probe: Option<ReadinessConfig>,
+    /// Fraction of probes allowed to fail during the soak
+    #[serde(default)]
+    pub(crate) max_error_rate: f64,
+    /// Times docker may restart the canary during the soak
+    #[serde(default)]
+    pub(crate) max_restarts: u64,
+}
+
+impl CanaryConfig {
+    fn default_suffix() -> String {
+        "canary".to_string()
+    }
+
+    fn default_interval() -> u64 {
+        10
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.suffix.is_empty() {
+            anyhow::bail!("suffix must not be empty");
+        }
+        if self.interval == 0 {
+            anyhow::bail!("interval must be at least 1 second");
+        }
+        if !(0.0..=1.0).contains(&self.max_error_rate) {
+            anyhow::bail!("max_error_rate must be between 0 and 1");
+        }
+        for port in &self.ports {
+            port.validate()
+                .with_context(|| format!("invalid port mapping {}", port))?;
+        }
+        if let Some(probe) = &self.probe {
+            probe.validate().context("invalid probe")?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Debug, Default, Clone)]
-pub(crate) struct PortConfig {
-    pub(crate) host: u32,
-    pub(crate) target: u32,
+pub(crate) struct HooksConfig {
+    /// Run in order before the old container is stopped; any failure aborts the deploy
+    #[serde(default)]
+    pub(crate) pre_deploy: Vec<HookConfig>,
+    /// Run in order once the new container has started
+    #[serde(default)]
+    pub(crate) post_deploy: Vec<HookConfig>,
+    /// Go back to the previous image if a post-deploy hook fails
+    #[serde(default)]
+    pub(crate) rollback_on_failure: bool,
 }
 
+/// A deploy step. Exactly one of `command` and `http` is set.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct HookConfig {
+    pub(crate) name: String,
+    /// Command to run in a one-off container of the image being deployed
+    pub(crate) command: Option<Vec<String>>,
+    /// URL to GET, which must return a success status
+    pub(crate) http: Option<String>,
+    /// Seconds to wait for the hook to finish before failing it
+    #[serde(default = "HookConfig::default_timeout")]
+    pub(crate) timeout: u64,
+}
+
+impl HookConfig {
+    fn default_timeout() -> u64 {
+        300
+    }
+
+    fn validate(&self) -> Result<()> {
+        match (&self.command, &self.http) {
+            (Some(command), None) if !command.is_empty() => Ok(()),
+            (Some(_), None) => anyhow::bail!("command must not be empty"),
+            (None, Some(_)) => Ok(()),
+            _ => anyhow::bail!("exactly one of command and http must be set"),
+        }
+    }
+}
+
+/// Limits and hardening applied to the container, left to docker's defaults when unset. There is
+/// no `ulimits` or `init`, as the docker client library cannot send them.
 #[derive(Deserialize, Debug, Default, Clone)]
+pub(crate) struct RuntimeConfig {
+    /// Memory limit in bytes
+    pub(crate) memory: Option<u64>,
+    /// Memory plus swap limit in bytes, or -1 for unlimited swap
+    pub(crate) memory_swap: Option<i64>,
+    /// Number of CPUs the container may use, e.g. 1.5
+    pub(crate) cpus: Option<f64>,
+    /// Relative CPU weight against other containers
+    pub(crate) cpu_shares: Option<u64>,
+    pub(crate) pids_limit: Option<u64>,
+    pub(crate) restart_policy: Option<RestartPolicy>,
+    /// How many times `on-failure` restarts the container before giving up
+    pub(crate) restart_max_retries: Option<isize>,
+    #[serde(default)]
+    pub(crate) read_only_rootfs: bool,
+    #[serde(default)]
+    pub(crate) cap_add: Vec<String>,
+    #[serde(default)]
+    pub(crate) cap_drop: Vec<String>,
+    pub(crate) user: Option<String>,
+    pub(crate) working_dir: Option<String>,
+    pub(crate) entrypoint: Option<Vec<String>>,
+}
+
+impl RuntimeConfig {
+    fn validate(&self) -> Result<()> {
+        if let Some(cpus) = self.cpus {
+            if cpus.is_nan() || cpus <= 0.0 {
+                anyhow::bail!("cpus must be greater than zero");
+            }
+        }
+        if self.restart_max_retries.is_some()
+            && self.restart_policy != Some(RestartPolicy::OnFailure)
+        {
+            anyhow::bail!("restart_max_retries only applies to the on-failure restart policy");
+        }
+        Ok(())
+    }
+
+    /// `cpus` in the billionths of a CPU the docker API expects
+    pub(crate) fn nano_cpus(&self) -> Option<u64> {
+        self.cpus.map(|cpus| (cpus * 1e9) as u64)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum RestartPolicy {
+    No,
+    Always,
+    UnlessStopped,
+    OnFailure,
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            RestartPolicy::No => "no",
+            RestartPolicy::Always => "always",
+            RestartPolicy::UnlessStopped => "unless-stopped",
+            RestartPolicy::OnFailure => "on-failure",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct PortConfig {
+    pub(crate) host: PortRange,
+    pub(crate) target: PortRange,
+    #[serde(default)]
+    pub(crate) protocol: Protocol,
+    /// Host interface to bind to, defaulting to all of them
+    pub(crate) host_ip: Option<String>,
+}
+
+impl PortConfig {
+    fn validate(&self) -> Result<()> {
+        if let Some(ip) = &self.host_ip {
+            ip.parse::<IpAddr>()
+                .with_context(|| format!("parsing host_ip `{}`", ip))?;
+        }
+        if self.host.len() != self.target.len() {
+            anyhow::bail!("host and target port ranges must be the same length");
+        }
+        Ok(())
+    }
+
+    /// The same mapping with the host ports moved up by `offset`
+    pub(crate) fn shifted(&self, offset: u16) -> Result<PortConfig> {
+        let shift = |port: u16| {
+            port.checked_add(offset).with_context(|| {
+                format!("host port {} shifted by {} is out of range", port, offset)
+            })
+        };
+        Ok(PortConfig {
+            host: PortRange::new(shift(self.host.start)?, shift(self.host.end)?)?,
+            ..self.clone()
+        })
+    }
+
+    /// Each host port paired with the container port it maps to
+    pub(crate) fn pairs(&self) -> impl Iterator<Item = (u16, u16)> {
+        self.host.iter().zip(self.target.iter())
+    }
+}
+
+impl fmt::Display for PortConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ip) = &self.host_ip {
+            write!(f, "{}:", ip)?;
+        }
+        write!(f, "{}:{}/{}", self.host, self.target, self.protocol)
+    }
+}
+
+/// A single port, or an inclusive range written as `"8000-8010"`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "PortSpec")]
+pub(crate) struct PortRange {
+    pub(crate) start: u16,
+    pub(crate) end: u16,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortSpec {
+    Single(u16),
+    Range(String),
+}
+
+impl TryFrom<PortSpec> for PortRange {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: PortSpec) -> Result<Self> {
+        match spec {
+            PortSpec::Single(port) => PortRange::new(port, port),
+            PortSpec::Range(s) => s.parse(),
+        }
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse = |p: &str| {
+            p.trim()
+                .parse::<u16>()
+                .with_context(|| format!("parsing port `{}`", p))
+        };
+
+        match s.split_once('-') {
+            Some((start, end)) => PortRange::new(parse(start)?, parse(end)?),
+            None => {
+                let port = parse(s)?;
+                PortRange::new(port, port)
+            }
+        }
+    }
+}
+
+impl PortRange {
+    fn new(start: u16, end: u16) -> Result<Self> {
+        if start == 0 {
+            anyhow::bail!("port 0 is not a valid port");
+        }
+        if start > end {
+            anyhow::bail!("port range {}-{} is backwards", start, end);
+        }
+        Ok(PortRange { start, end })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        usize::from(self.end - self.start) + 1
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = u16> {
+        self.start..=self.end
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+    Sctp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::Sctp => "sctp",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct NetworkConfig {
+    pub(crate) name: String,
+    /// Extra names the container can be reached by on this network
+    #[serde(default)]
+    pub(crate) aliases: Vec<String>,
+    pub(crate) ipv4_address: Option<String>,
+    pub(crate) ipv6_address: Option<String>,
+    /// The deployer owns this network, and creates it if it does not exist
+    #[serde(default)]
+    pub(crate) create: bool,
+    /// Driver for a network the deployer creates, defaulting to `bridge`
+    pub(crate) driver: Option<String>,
+    /// Subnet for a network the deployer creates, needed to use static addresses
+    pub(crate) subnet: Option<String>,
+}
+
+impl NetworkConfig {
+    fn validate(&self) -> Result<()> {
+        if let Some(ip) = &self.ipv4_address {
+            ip.parse::<std::net::Ipv4Addr>()
+                .with_context(|| format!("parsing ipv4_address `{}`", ip))?;
+        }
+        if let Some(ip) = &self.ipv6_address {
+            ip.parse::<std::net::Ipv6Addr>()
+                .with_context(|| format!("parsing ipv6_address `{}`", ip))?;
+        }
+        if !self.create && (self.driver.is_some() || self.subnet.is_some()) {
+            anyhow::bail!("driver and subnet only apply to networks with create = true");
+        }
+        Ok(())
+    }
+
+    /// The same network without the service's aliases and static addresses, for containers that
+    /// join it alongside the service
+    pub(crate) fn anonymous(&self) -> NetworkConfig {
+        NetworkConfig {
+            aliases: Vec::new(),
+            ipv4_address: None,
+            ipv6_address: None,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub(crate) struct MountConfig {
-    pub(crate) host: String,
+    #[serde(rename = "type", default)]
+    pub(crate) kind: MountType,
+    /// Host path of a bind mount, or the name of a volume. Not used for tmpfs mounts.
+    #[serde(alias = "source")]
+    pub(crate) host: Option<String>,
     pub(crate) target: String,
+    #[serde(default)]
+    pub(crate) read_only: bool,
+    /// Bind mounts only
+    pub(crate) propagation: Option<Propagation>,
+    /// Bind mounts only
+    pub(crate) selinux_label: Option<SelinuxLabel>,
+    /// Tmpfs mounts only, in bytes
+    pub(crate) tmpfs_size: Option<u64>,
+    /// Tmpfs mounts only, as an octal permission string such as `"1770"`
+    pub(crate) tmpfs_mode: Option<String>,
+}
+
+impl MountConfig {
+    fn validate(&self) -> Result<()> {
+        if !self.target.starts_with('/') {
+            anyhow::bail!("target must be an absolute path");
+        }
+
+        match (self.kind, &self.host) {
+            (MountType::Bind, None) | (MountType::Volume, None) => {
+                anyhow::bail!("{} mounts need a host path or volume name", self.kind)
+            }
+            (MountType::Tmpfs, Some(_)) => anyhow::bail!("tmpfs mounts cannot have a host path"),
+            _ => {}
+        }
+
+        if self.kind != MountType::Bind
+            && (self.propagation.is_some() || self.selinux_label.is_some())
+        {
+            anyhow::bail!("propagation and selinux_label only apply to bind mounts");
+        }
+
+        if self.kind != MountType::Tmpfs && (self.tmpfs_size.is_some() || self.tmpfs_mode.is_some())
+        {
+            anyhow::bail!("tmpfs_size and tmpfs_mode only apply to tmpfs mounts");
+        }
+
+        self.tmpfs_mode()?;
+        Ok(())
+    }
+
+    pub(crate) fn tmpfs_mode(&self) -> Result<Option<u32>> {
+        self.tmpfs_mode
+            .as_ref()
+            .map(|mode| {
+                u32::from_str_radix(mode, 8)
+                    .with_context(|| format!("parsing tmpfs_mode `{}` as octal", mode))
+            })
+            .transpose()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MountType {
+    #[default]
+    Bind,
+    Volume,
+    Tmpfs,
+}
+
+impl fmt::Display for MountType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            MountType::Bind => "bind",
+            MountType::Volume => "volume",
+            MountType::Tmpfs => "tmpfs",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Propagation {
+    Private,
+    Rprivate,
+    Shared,
+    Rshared,
+    Slave,
+    Rslave,
+}
+
+impl fmt::Display for Propagation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Propagation::Private => "private",
+            Propagation::Rprivate => "rprivate",
+            Propagation::Shared => "shared",
+            Propagation::Rshared => "rshared",
+            Propagation::Slave => "slave",
+            Propagation::Rslave => "rslave",
+        };
+        f.write_str(s)
+    }
+}
+
+/// How docker should relabel a bind mount for SELinux
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SelinuxLabel {
+    /// Shared between containers, docker's `z` option
+    Shared,
+    /// Private to this container, docker's `Z` option
+    Private,
 }
 
 #[derive(Deserialize, Debug, Default)]
+#[allow(dead_code)]
 pub struct BranchConfig {
     pub(crate) name: String,
     pub(crate) build_on_failure: bool,
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Debug, Default, Clone)]
 pub struct HeartbeatConfig {
-    pub(crate) sleep_time: i64,
+    /// Seconds between heartbeats
+    pub(crate) sleep_time: u64,
+    /// Path of the liveness endpoint served by the daemon
     pub(crate) endpoint: String,
+    /// Optional URL to send a heartbeat to, e.g. a dead man's switch monitor
+    pub(crate) url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub(crate) struct NotificationsConfig {
+    #[serde(default)]
+    pub(crate) webhooks: Vec<WebhookConfig>,
+    #[serde(default)]
+    pub(crate) email: Vec<EmailConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct WebhookConfig {
+    pub(crate) url: String,
+    #[serde(default)]
+    pub(crate) format: WebhookFormat,
+    /// JSON body with `{{variable}}` placeholders, replacing the format's default body
+    pub(crate) template: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct EmailConfig {
+    pub(crate) host: String,
+    #[serde(default = "default_smtp_port")]
+    pub(crate) port: u16,
+    /// Upgrade the connection with STARTTLS, and fail if the server does not support it
+    #[serde(default = "default_true")]
+    pub(crate) starttls: bool,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    pub(crate) from: String,
+    pub(crate) to: Vec<String>,
+}
+
+// Keeps the SMTP password out of logs, as the config is logged on startup and reload
+impl fmt::Debug for EmailConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmailConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("starttls", &self.starttls)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .finish()
+    }
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum WebhookFormat {
+    #[default]
+    Generic,
+    Slack,
+    Mattermost,
+    Teams,
 }
 
 #[cfg(test)]
@@ -73,4 +1228,321 @@ mod tests {
         // This test makes sure the example config stays in line with the parsing code.
         let _config = DockerDeployConfig::from_file("config.toml.example");
     }
+
+    #[test]
+    fn test_static_address_replicas() {
+        let validate = |replicas: u16| {
+            let text = format!(
+                r#"
+                api_version = "1"
+
+                [image]
+                name = "foo"
+                tag = "latest"
+
+                [container]
+                name = "foo"
+                command = []
+                ports = []
+                mounts = []
+                replicas = {}
+
+                [[container.networks]]
+                name = "backend"
+                ipv4_address = "172.20.0.10"
+
+                [branch]
+                name = "master"
+                build_on_failure = false
+
+                [heartbeat]
+                sleep_time = 10
+                endpoint = "/heartbeat"
+                "#,
+                replicas
+            );
+            toml::from_str::<DockerDeployConfig>(&text)
+                .unwrap()
+                .validate()
+        };
+
+        assert!(validate(1).is_ok());
+        assert!(validate(2).is_err());
+    }
+
+    fn parse_port(text: &str) -> Result<PortConfig> {
+        let port: PortConfig = toml::from_str(text)?;
+        port.validate()?;
+        Ok(port)
+    }
+
+    #[test]
+    fn test_parse_single_port() {
+        let port = parse_port("host = 5020\ntarget = 80").unwrap();
+
+        assert_eq!(port.protocol, Protocol::Tcp);
+        assert_eq!(port.host_ip, None);
+        assert_eq!(port.pairs().collect::<Vec<_>>(), vec![(5020, 80)]);
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        let port = parse_port(
+            "host = \"6000-6002\"\ntarget = \"7000-7002\"\nprotocol = \"udp\"\nhost_ip = \"127.0.0.1\"",
+        )
+        .unwrap();
+
+        assert_eq!(port.protocol, Protocol::Udp);
+        assert_eq!(
+            port.pairs().collect::<Vec<_>>(),
+            vec![(6000, 7000), (6001, 7001), (6002, 7002)]
+        );
+        assert_eq!(port.to_string(), "127.0.0.1:6000-6002:7000-7002/udp");
+    }
+
+    #[test]
+    fn test_container_hash() {
+        let a = "[image]\nname = \"a\"\n[container]\nname = \"foo\"\ncommand = [\"x\"]\n";
+        let b = "[container]\ncommand = [\"x\"]\n\nname   = \"foo\"\n[image]\nname = \"b\"\n";
+        let c = "[container]\nname = \"foo\"\ncommand = [\"y\"]\n";
+
+        assert_eq!(container_hash(a).unwrap(), container_hash(b).unwrap());
+        assert_ne!(container_hash(a).unwrap(), container_hash(c).unwrap());
+    }
+
+    #[test]
+    fn test_sidecar_config() {
+        let text = "[container]\nname = \"foo\"\n\n[[sidecars]]\nname = \"redis\"\nimage = \"redis\"\n\n\
+                    [[sidecars]]\nname = \"shipper\"\nimage = \"vector\"\ndepends_on = [\"redis\"]\n";
+        let hashes = sidecar_hashes(text).unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert_ne!(hashes[0], hashes[1]);
+        assert_eq!(
+            sidecar_hashes("[container]\nname = \"foo\"\n")
+                .unwrap()
+                .len(),
+            0
+        );
+
+        let parse = |text: &str| {
+            let sidecar: SidecarConfig = toml::from_str(text).unwrap();
+            sidecar.validate().map(|_| sidecar)
+        };
+        let sidecar = parse("name = \"redis\"\nimage = \"redis\"").unwrap();
+        assert_eq!(sidecar.reference(), "redis:latest");
+        assert!(parse("name = \"2\"\nimage = \"redis\"").is_err());
+        assert!(parse("name = \"my cache\"\nimage = \"redis\"").is_err());
+        assert!(
+            parse("name = \"redis\"\nimage = \"redis\"\n[stop.pre_stop]\nexec = [\"x\"]").is_err()
+        );
+    }
+
+    #[test]
+    fn test_readiness_config() {
+        let parse = |text: &str| {
+            let readiness: ReadinessConfig = toml::from_str(text).unwrap();
+            readiness.validate().map(|_| readiness)
+        };
+
+        let readiness = parse("tcp = \"127.0.0.1:5020\"").unwrap();
+        assert_eq!((readiness.timeout, readiness.interval), (60, 2));
+        assert!(parse("http = \"http://localhost/health\"\nexpected_status = 204").is_ok());
+        assert!(parse("timeout = 30").is_err());
+        assert!(parse("tcp = \"localhost\"").is_err());
+        assert!(parse("tcp = \"127.0.0.1:5020\"\nexpected_status = 200").is_err());
+        assert!(parse("tcp = \"127.0.0.1:5020\"\ninterval = 0").is_err());
+    }
+
+    #[test]
+    fn test_canary_config() {
+        let parse = |text: &str| {
+            let canary: CanaryConfig = toml::from_str(text).unwrap();
+            canary.validate().map(|_| canary)
+        };
+
+        let canary = parse("duration = 300").unwrap();
+        assert_eq!(canary.suffix, "canary");
+        assert_eq!(canary.interval, 10);
+        assert!(parse("duration = 300\n[probe]\ntcp = \"127.0.0.1:5021\"").is_ok());
+        assert!(parse("duration = 300\nmax_error_rate = 1.5").is_err());
+        assert!(parse("duration = 300\ninterval = 0").is_err());
+        assert!(parse("duration = 300\nsuffix = \"\"").is_err());
+        assert!(parse("duration = 300\n[probe]\ntimeout = 30").is_err());
+    }
+
+    #[test]
+    fn test_auth_config() {
+        let parse = |text: &str| {
+            let auth: AuthConfig = toml::from_str(text).unwrap();
+            auth.validate().map(|_| auth)
+        };
+        let token = |name: &str, token: &str, scopes: &str| {
+            format!(
+                "[[tokens]]\nname = \"{}\"\ntoken = \"{}\"\nscopes = {}\n",
+                name, token, scopes
+            )
+        };
+        let secret = "0123456789abcdef";
+
+        let auth = parse(&(token("ci", secret, "[\"deploy\", \"read\"]"))).unwrap();
+        assert_eq!(auth.tokens[0].scopes, vec![Scope::Deploy, Scope::Read]);
+        assert!(!format!("{:?}", auth).contains(secret));
+
+        assert!(parse("").is_err());
+        assert!(parse(&token("ci", "short", "[\"read\"]")).is_err());
+        assert!(parse(&token("ci", secret, "[]")).is_err());
+        let twice = token("ci", secret, "[\"read\"]") + &token("ci", secret, "[\"admin\"]");
+        assert!(parse(&twice).is_err());
+    }
+
+    #[test]
+    fn test_email_password_redacted() {
+        let email: EmailConfig = toml::from_str(
+            "host = \"smtp.example.com\"\nusername = \"dockerdeploy\"\npassword = \"my-smtp-password\"\nfrom = \"dockerdeploy@example.com\"\nto = [\"oncall@example.com\"]",
+        )
+        .unwrap();
+
+        let debug = format!("{:?}", email);
+        assert!(!debug.contains("my-smtp-password"));
+        assert!(debug.contains("dockerdeploy@example.com"));
+    }
+
+    #[test]
+    fn test_hooks_config() {
+        let parse = |text: &str| {
+            let hook: HookConfig = toml::from_str(text).unwrap();
+            hook.validate().map(|_| hook)
+        };
+
+        let hook = parse("name = \"migrate\"\ncommand = [\"./migrate\"]").unwrap();
+        assert_eq!(hook.timeout, 300);
+        assert!(parse("name = \"smoke\"\nhttp = \"http://localhost/health\"").is_ok());
+        assert!(parse("name = \"migrate\"\ncommand = []").is_err());
+        assert!(parse("name = \"nothing\"").is_err());
+        assert!(parse("name = \"both\"\ncommand = [\"x\"]\nhttp = \"http://localhost\"").is_err());
+    }
+
+    #[test]
+    fn test_stop_config() {
+        let parse = |text: &str| {
+            let stop: StopConfig = toml::from_str(text).unwrap();
+            stop.pre_stop
+                .as_ref()
+                .map_or(Ok(()), |p| p.validate())
+                .map(|_| stop)
+        };
+
+        let stop = parse("signal = \"SIGINT\"").unwrap();
+        assert_eq!(stop.timeout, 10);
+
+        assert!(parse("[pre_stop]\nhttp = \"http://localhost/drain\"").is_ok());
+        assert!(parse("[pre_stop]\nexec = [\"/drain\"]\ntimeout = 5").is_ok());
+        assert!(parse("[pre_stop]\nexec = []").is_err());
+        assert!(parse("[pre_stop]\ntimeout = 5").is_err());
+        assert!(
+            parse("[pre_stop]\nhttp = \"http://localhost/drain\"\nexec = [\"/drain\"]").is_err()
+        );
+    }
+
+    #[test]
+    fn test_runtime_config() {
+        let parse = |text: &str| {
+            let runtime: RuntimeConfig = toml::from_str(text).unwrap();
+            runtime.validate().map(|_| runtime)
+        };
+
+        let runtime =
+            parse("cpus = 1.5\nrestart_policy = \"on-failure\"\nrestart_max_retries = 3").unwrap();
+        assert_eq!(runtime.nano_cpus(), Some(1_500_000_000));
+        assert_eq!(runtime.restart_policy, Some(RestartPolicy::OnFailure));
+
+        assert!(parse("cpus = 0.0").is_err());
+        assert!(parse("restart_policy = \"always\"\nrestart_max_retries = 3").is_err());
+    }
+
+    #[test]
+    fn test_invalid_networks() {
+        let parse = |text: &str| {
+            let network: NetworkConfig = toml::from_str(text).unwrap();
+            network.validate()
+        };
+
+        assert!(
+            parse("name = \"backend\"\naliases = [\"app\"]\nipv4_address = \"172.20.0.10\"")
+                .is_ok()
+        );
+        assert!(parse("name = \"backend\"\ncreate = true\nsubnet = \"172.20.0.0/16\"").is_ok());
+        assert!(parse("name = \"backend\"\nipv4_address = \"::1\"").is_err());
+        assert!(parse("name = \"backend\"\nsubnet = \"172.20.0.0/16\"").is_err());
+    }
+
+    #[test]
+    fn test_invalid_mounts() {
+        let parse = |text: &str| {
+            let mount: MountConfig = toml::from_str(text).unwrap();
+            mount.validate()
+        };
+
+        assert!(parse("host = \"data\"\ntarget = \"/data\"").is_ok());
+        assert!(parse("type = \"tmpfs\"\ntarget = \"/tmp\"\ntmpfs_mode = \"1777\"").is_ok());
+        // Relative target
+        assert!(parse("host = \"data\"\ntarget = \"data\"").is_err());
+        // Volume without a name
+        assert!(parse("type = \"volume\"\ntarget = \"/data\"").is_err());
+        // Tmpfs with a source
+        assert!(parse("type = \"tmpfs\"\nhost = \"data\"\ntarget = \"/tmp\"").is_err());
+        // Bind options on a volume
+        assert!(parse(
+            "type = \"volume\"\nhost = \"data\"\ntarget = \"/data\"\nselinux_label = \"shared\""
+        )
+        .is_err());
+        // Mode is not octal
+        assert!(parse("type = \"tmpfs\"\ntarget = \"/tmp\"\ntmpfs_mode = \"999\"").is_err());
+    }
+
+    #[test]
+    fn test_resolve_relative_bind_mounts() {
+        let mut config = DockerDeployConfig::default();
+        config.container.mounts = vec![
+            toml::from_str("host = \"data\"\ntarget = \"/data\"").unwrap(),
+            toml::from_str("host = \"/srv/static\"\ntarget = \"/static\"").unwrap(),
+            toml::from_str("type = \"volume\"\nhost = \"cache\"\ntarget = \"/cache\"").unwrap(),
+            toml::from_str("host = \"$PWD/logs\"\ntarget = \"/logs\"").unwrap(),
+        ];
+
+        config
+            .resolve_paths(Path::new("/etc/dockerdeploy"))
+            .unwrap();
+
+        let hosts: Vec<_> = config
+            .container
+            .mounts
+            .iter()
+            .map(|m| m.host.as_deref().unwrap())
+            .collect();
+        let logs = std::env::current_dir().unwrap().join("logs");
+        assert_eq!(
+            hosts,
+            vec![
+                "/etc/dockerdeploy/data",
+                "/srv/static",
+                "cache",
+                logs.to_str().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_ports() {
+        // Mismatched range lengths
+        assert!(parse_port("host = \"6000-6002\"\ntarget = 80").is_err());
+        // Backwards range
+        assert!(parse_port("host = \"6002-6000\"\ntarget = \"80-82\"").is_err());
+        // Out of range
+        assert!(parse_port("host = 70000\ntarget = 80").is_err());
+        // Bad host IP
+        assert!(parse_port("host = 80\ntarget = 80\nhost_ip = \"localhost\"").is_err());
+        // Unknown protocol
+        assert!(parse_port("host = 80\ntarget = 80\nprotocol = \"icmp\"").is_err());
+    }
 }
@@ -0,0 +1,152 @@
+//! Per-deployment logs
+//!
+//! While a deployment runs, every record logged from its task is prefixed with the deployment's
+//! id, and a copy is kept with the deployment so its steps can be read back through
+//! `GET /deployments/{id}` without the poll loop's noise in between.
+
+use chrono::{DateTime, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// How many lines to keep for one deployment
+const MAX_LINES: usize = 2000;
+
+tokio::task_local! {
+    static CURRENT: Capture;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct LogLine {
+    pub(crate) time: DateTime<Utc>,
+    pub(crate) level: String,
+    pub(crate) message: String,
+}
+
+/// The lines logged by one deployment so far
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DeployLog(Arc<Mutex<Vec<LogLine>>>);
+
+impl DeployLog {
+    pub(crate) fn lines(&self) -> Vec<LogLine> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn push(&self, line: LogLine) {
+        let mut lines = self.0.lock().unwrap();
+        if lines.len() < MAX_LINES {
+            lines.push(line);
+        } else if lines.len() == MAX_LINES {
+            lines.push(LogLine {
+                time: line.time,
+                level: Level::Warn.to_string(),
+                message: format!("log truncated after {} lines", MAX_LINES),
+            });
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Capture {
+    id: Uuid,
+    log: DeployLog,
+}
+
+/// Run `f`, tagging and capturing what it logs as deployment `id`'s
+pub(crate) async fn scope<F: Future>(id: Uuid, log: DeployLog, f: F) -> F::Output {
+    CURRENT.scope(Capture { id, log }, f).await
+}
+
+/// The id of the deployment the current task is running, if any
+pub(crate) fn current_id() -> Option<Uuid> {
+    CURRENT.try_with(|capture| capture.id).ok()
+}
+
+/// Install the logger, configured from `RUST_LOG` like `env_logger::init`
+pub(crate) fn init() {
+    let inner = env_logger::Builder::from_default_env().build();
+    // Deployments capture this crate's debug records even when they are not printed
+    log::set_max_level(inner.filter().max(LevelFilter::Debug));
+    log::set_boxed_logger(Box::new(Logger { inner })).expect("logger already set");
+}
+
+/// Whether a record belongs in a deployment's log: everything this crate logs at debug and above,
+/// and warnings and errors from the libraries it uses
+fn captured(metadata: &Metadata) -> bool {
+    let ours = metadata.target().split("::").next() == Some(env!("CARGO_PKG_NAME"));
+    let level = if ours { Level::Debug } else { Level::Warn };
+    metadata.level() <= level
+}
+
+struct Logger {
+    inner: env_logger::Logger,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata) || (captured(metadata) && CURRENT.try_with(|_| ()).is_ok())
+    }
+
+    fn log(&self, record: &Record) {
+        let capture = match CURRENT.try_with(Capture::clone) {
+            Ok(capture) => capture,
+            Err(_) => return self.inner.log(record),
+        };
+
+        if captured(record.metadata()) {
+            capture.log.push(LogLine {
+                time: Utc::now(),
+                level: record.level().to_string(),
+                message: record.args().to_string(),
+            });
+        }
+        self.inner.log(
+            &Record::builder()
+                .args(format_args!("[deploy {}] {}", capture.id, record.args()))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_captured() {
+        let is_captured = |target: &str, level: Level| {
+            captured(&Metadata::builder().target(target).level(level).build())
+        };
+
+        assert!(is_captured("dockerdeploy::sidecars", Level::Debug));
+        assert!(!is_captured("dockerdeploy", Level::Trace));
+        assert!(!is_captured("hyper::client", Level::Info));
+        assert!(is_captured("bollard::docker", Level::Warn));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let log = DeployLog::default();
+        for i in 0..MAX_LINES + 10 {
+            log.push(LogLine {
+                time: Utc::now(),
+                level: "INFO".to_string(),
+                message: i.to_string(),
+            });
+        }
+
+        let lines = log.lines();
+        assert_eq!(lines.len(), MAX_LINES + 1);
+        assert!(lines[MAX_LINES].message.contains("truncated"));
+    }
+}
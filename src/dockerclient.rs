@@ -1,16 +1,145 @@
-use anyhow::Result;
+use crate::config::{
+    HealthcheckConfig, MountConfig, MountType, NetworkConfig, PortConfig, RuntimeConfig,
+    SelinuxLabel, StopConfig,
+};
+use anyhow::{Context, Result};
 use async_trait::async_trait;
-use bollard::container::PortBinding;
+use bollard::container::{
+    ContainerNetwork, HealthConfig, MountPoint, MountPointBindOptions, MountPointTmpfsOptions,
+    PortBinding,
+};
+use bollard::image::BuildImageQueryParams;
+use bollard::network::EndpointIPAMConfig;
 use bollard::Docker;
-use std::collections::HashMap;
-use tokio::stream::StreamExt;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
+use tokio::stream::{Stream, StreamExt};
 
 pub(crate) struct RunContainerOptions<'a> {
     pub(crate) name: &'a str,
     pub(crate) image: &'a str,
     pub(crate) cmd: Vec<&'a str>,
-    pub(crate) ports: Vec<crate::config::PortConfig>,
-    pub(crate) mounts: Vec<crate::config::MountConfig>,
+    pub(crate) ports: Vec<PortConfig>,
+    pub(crate) mounts: Vec<MountConfig>,
+    pub(crate) networks: Vec<NetworkConfig>,
+    pub(crate) network_mode: Option<String>,
+    pub(crate) runtime: RuntimeConfig,
+    pub(crate) labels: HashMap<String, String>,
+    pub(crate) env: BTreeMap<String, String>,
+    pub(crate) healthcheck: Option<HealthcheckConfig>,
+    pub(crate) stop: StopConfig,
+}
+
+/// What to build, for [`DockerApi::build_image`]
+#[derive(Debug, Clone)]
+pub(crate) struct BuildOptions {
+    /// Path to the Dockerfile within the build context
+    pub(crate) dockerfile: String,
+    /// `name:tag` references to tag the image with
+    pub(crate) tags: Vec<String>,
+    pub(crate) args: BTreeMap<String, String>,
+    pub(crate) target: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ContainerDetails {
+    pub(crate) running: bool,
+    /// How many times docker's restart policy has restarted the container
+    pub(crate) restart_count: u64,
+    /// Health check status, for containers with a health check
+    pub(crate) health: Option<String>,
+    pub(crate) exit_code: i64,
+    pub(crate) oom_killed: bool,
+    pub(crate) finished_at: Option<DateTime<Utc>>,
+    pub(crate) error: Option<String>,
+}
+
+pub(crate) type EventStream = Pin<Box<dyn Stream<Item = Result<ContainerEvent>> + Send>>;
+
+/// Something that happened to a container, as reported by docker's event stream
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct ContainerEvent {
+    pub(crate) action: ContainerAction,
+    pub(crate) container_id: String,
+    pub(crate) name: String,
+    /// The container's `dockerdeploy.service` label
+    pub(crate) service: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) enum ContainerAction {
+    Die,
+    Oom,
+    /// The new health check status, e.g. `healthy` or `unhealthy`
+    HealthStatus(String),
+    Destroy,
+}
+
+/// The events the daemon acts on; docker sends many more
+const EVENT_ACTIONS: &[&str] = &["die", "oom", "health_status", "destroy"];
+
+/// Whether an error from docker means the object asked for does not exist
+pub(crate) fn is_not_found(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<bollard::errors::Error>().map(|e| e.kind()),
+        Some(bollard::errors::ErrorKind::DockerResponseNotFoundError { .. })
+    )
+}
+
+fn container_event(event: bollard::system::EventsResults) -> Option<ContainerEvent> {
+    if event.type_ != "container" {
+        return None;
+    }
+
+    // Health events come through as e.g. `health_status: unhealthy`
+    let action = match event.action.split(": ").collect::<Vec<_>>().as_slice() {
+        ["die"] => ContainerAction::Die,
+        ["oom"] => ContainerAction::Oom,
+        ["destroy"] => ContainerAction::Destroy,
+        ["health_status", status] => ContainerAction::HealthStatus((*status).to_string()),
+        _ => return None,
+    };
+    let attributes = event.actor.attributes;
+
+    Some(ContainerEvent {
+        action,
+        container_id: event.actor.id,
+        name: attributes.get("name").cloned().unwrap_or_default(),
+        service: attributes.get(crate::labels::SERVICE).cloned(),
+    })
+}
+
+/// Chunks of log output, which need not line up with line boundaries
+pub(crate) type LogStream = Pin<Box<dyn Stream<Item = Result<String>> + Send>>;
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LogsRequest {
+    /// Keep streaming new output until the container stops
+    pub(crate) follow: bool,
+    /// Only send this many lines from the end of the existing logs
+    pub(crate) tail: Option<u64>,
+    /// Only send output written after this time
+    pub(crate) since: Option<DateTime<Utc>>,
+}
+
+pub(crate) enum ContainerFilter<'a> {
+    /// Containers carrying a label, given as `key` or `key=value`
+    Label(&'a str),
+    /// The container with exactly this name
+    Name(&'a str),
+    /// The container with exactly this name, or this name followed by a replica's `-N`
+    Replicas(&'a str),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ContainerSummary {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) running: bool,
+    pub(crate) labels: HashMap<String, String>,
+    pub(crate) created: Option<DateTime<Utc>>,
 }
 
 pub(crate) struct CreateImageOptions<'a> {
@@ -22,41 +151,192 @@ pub(crate) struct CreateContainerResults {
     pub(crate) warnings: Vec<String>,
 }
 
+pub(crate) struct ImageDetails {
+    pub(crate) id: String,
+    pub(crate) digest: Option<String>,
+}
+
 #[async_trait]
 pub(crate) trait DockerApi {
-    async fn is_container_running(&self, container_name: &str) -> Result<bool>;
+    /// Every container matching `filter`, running or not
+    async fn list_containers(&self, filter: ContainerFilter<'_>) -> Result<Vec<ContainerSummary>>;
+
+    /// Send the container's stop signal, killing it if it has not exited after `timeout` seconds
+    async fn stop_container(&self, container_name: &str, timeout: u64) -> Result<()>;
+
+    /// Wait for a container to exit, returning its exit code
+    async fn wait_container(&self, container_name: &str) -> Result<i64>;
 
+    /// The container's current state, or how it exited if it has stopped
+    async fn inspect_container(&self, container_name: &str) -> Result<ContainerDetails>;
+
+    /// Forcibly remove a container, whether or not it is running
     async fn remove_container(&self, container_name: &str) -> Result<()>;
 
+    /// Run a command inside a running container, returning its exit code
+    async fn exec(&self, container_name: &str, cmd: &[String]) -> Result<i64>;
+
     async fn run_container<'a>(
         &'a self,
         options: RunContainerOptions<'a>,
     ) -> Result<CreateContainerResults>;
 
     async fn create_image<'a>(&'a self, options: CreateImageOptions<'a>) -> Result<()>;
+
+    /// Make sure a network exists, creating it if the deployer owns it
+    async fn ensure_network(&self, network: &NetworkConfig) -> Result<()>;
+
+    async fn inspect_image(&self, image: &str) -> Result<ImageDetails>;
+
+    /// Stream the container's stdout and stderr. Errors, including the container not existing,
+    /// come through the stream.
+    fn container_logs(&self, container_name: &str, options: LogsRequest) -> LogStream;
+
+    /// Subscribe to lifecycle events of containers carrying `label`, from now on
+    fn container_events(&self, label: &str) -> EventStream;
+
+    /// Build an image from a tar of its build context, streaming the build's output. The stream
+    /// ends with an error if the build fails.
+    fn build_image(&self, options: BuildOptions, context: Vec<u8>) -> LogStream;
 }
 
 #[async_trait]
 impl DockerApi for bollard::Docker {
-    async fn is_container_running(&self, container_name: &str) -> Result<bool> {
-        use bollard::container::InspectContainerOptions;
-        let options = Some(InspectContainerOptions { size: false });
+    async fn list_containers(&self, filter: ContainerFilter<'_>) -> Result<Vec<ContainerSummary>> {
+        use bollard::container::ListContainersOptions;
+
+        let (key, value) = match filter {
+            ContainerFilter::Label(label) => ("label", label.to_string()),
+            // Docker matches names as a regex against names with a leading slash
+            ContainerFilter::Name(name) => ("name", format!("^/{}$", name.replace('.', "\\."))),
+            ContainerFilter::Replicas(name) => {
+                ("name", format!("^/{}(-[0-9]+)?$", name.replace('.', "\\.")))
+            }
+        };
+        let options = Some(ListContainersOptions {
+            all: true,
+            filters: vec![(key, vec![value.as_str()])].into_iter().collect(),
+            ..Default::default()
+        });
 
-        match Docker::inspect_container(self, container_name, options).await {
-            Ok(_) => Ok(true),
+        let containers = Docker::list_containers(self, options).await?;
+        Ok(containers
+            .into_iter()
+            .map(|c| ContainerSummary {
+                name: c
+                    .names
+                    .first()
+                    .map(|name| name.trim_start_matches('/').to_string())
+                    .unwrap_or_default(),
+                running: c.state == "running",
+                id: c.id,
+                labels: c.labels,
+                created: Some(c.created),
+            })
+            .collect())
+    }
+
+    async fn stop_container(&self, container_name: &str, timeout: u64) -> Result<()> {
+        use bollard::container::StopContainerOptions;
+
+        let options = Some(StopContainerOptions { t: timeout as i64 });
+
+        match Docker::stop_container(self, container_name, options).await {
+            Ok(_) => Ok(()),
             Err(e) => match e.kind() {
-                bollard::errors::ErrorKind::DockerResponseNotFoundError { .. } => {
-                    log::info!("configured container not running, starting");
-                    Ok(false)
-                }
-                _ => {
-                    log::warn!("error inspecting container {}: {:?}", container_name, e);
-                    Err(e.into())
+                bollard::errors::ErrorKind::DockerResponseNotFoundError { .. }
+                | bollard::errors::ErrorKind::DockerResponseNotModifiedError { .. } => {
+                    log::debug!("container {} already stopped", container_name);
+                    Ok(())
                 }
+                _ => Err(e.into()),
             },
         }
     }
 
+    async fn exec(&self, container_name: &str, cmd: &[String]) -> Result<i64> {
+        use bollard::exec::{CreateExecOptions, StartExecResults};
+
+        let options = CreateExecOptions {
+            cmd: Some(cmd.to_vec()),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            ..Default::default()
+        };
+        let created = Docker::create_exec(self, container_name, options).await?;
+
+        // The output stream ends when the command exits
+        let mut output = Docker::start_exec(self, &created.id, None);
+        while let Some(res) = output.next().await {
+            if let StartExecResults::Attached { log } = res? {
+                log::debug!("exec in {}: {}", container_name, log.to_string().trim_end());
+            }
+        }
+
+        let inspect = Docker::inspect_exec(self, &created.id).await?;
+        inspect
+            .exit_code
+            .map(|code| code as i64)
+            .context("exec finished without an exit code")
+    }
+
+    fn container_events(&self, label: &str) -> EventStream {
+        use bollard::system::EventsOptions;
+
+        let filters = vec![
+            ("type", vec!["container"]),
+            ("label", vec![label]),
+            ("event", EVENT_ACTIONS.to_vec()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.into_iter().map(str::to_string).collect()))
+        .collect();
+        // bollard always sends `until`, so push it far enough out that the stream never ends
+        let options = Some(EventsOptions::<String> {
+            since: Utc::now(),
+            until: Utc::now() + chrono::Duration::days(365 * 100),
+            filters,
+        });
+
+        let stream = Docker::events(self, options).filter_map(|event| match event {
+            Ok(event) => container_event(event).map(Ok),
+            Err(e) => Some(Err(e.into())),
+        });
+        Box::pin(stream)
+    }
+
+    async fn wait_container(&self, container_name: &str) -> Result<i64> {
+        use bollard::container::WaitContainerOptions;
+
+        let options = Some(WaitContainerOptions {
+            condition: "not-running",
+        });
+        let mut results = Docker::wait_container(self, container_name, options);
+        match results.next().await {
+            Some(res) => Ok(res?.status_code as i64),
+            None => anyhow::bail!("no result waiting for container {}", container_name),
+        }
+    }
+
+    async fn inspect_container(&self, container_name: &str) -> Result<ContainerDetails> {
+        use bollard::container::InspectContainerOptions;
+
+        let options = Some(InspectContainerOptions { size: false });
+        let container = Docker::inspect_container(self, container_name, options).await?;
+        let state = container.state;
+
+        Ok(ContainerDetails {
+            running: state.running,
+            restart_count: container.restart_count.max(0) as u64,
+            health: state.health.map(|health| health.status),
+            exit_code: i64::from(state.exit_code),
+            oom_killed: state.oomkilled,
+            // Docker reports the zero time for containers that never finished
+            finished_at: Some(state.finished_at).filter(|t| t.timestamp() > 0),
+            error: Some(state.error).filter(|e| !e.is_empty()),
+        })
+    }
+
     async fn remove_container(&self, container_name: &str) -> Result<()> {
         use bollard::container::RemoveContainerOptions;
 
@@ -84,60 +364,97 @@ impl DockerApi for bollard::Docker {
         options: RunContainerOptions<'a>,
     ) -> Result<CreateContainerResults> {
         use bollard::container::{
-            Config, CreateContainerOptions, HostConfig, StartContainerOptions,
+            Config, CreateContainerOptions, HostConfig, NetworkingConfig, RestartPolicy,
+            StartContainerOptions,
         };
+        use bollard::network::{ConnectNetworkOptions, EndpointSettings};
 
         let c_options = Some(CreateContainerOptions { name: options.name });
 
-        let cwd = std::env::current_dir()?;
-
-        let binds = options
-            .mounts
-            .iter()
-            .map(|config| {
-                let host_path = cwd.join(config.host.replace("$PWD", &cwd.to_string_lossy()));
+        let (binds, mounts) = mounts(&options.mounts);
+        let runtime = &options.runtime;
 
-                format!("{}:{}", host_path.to_string_lossy(), config.target)
-            })
+        let port_bindings = port_bindings(&options.ports);
+        let exposed_ports = port_bindings
+            .keys()
+            .map(|port| (port.clone(), HashMap::new()))
             .collect();
 
-        let port_bindings = options
-            .ports
-            .iter()
-            .map(|config| {
+        // The first network is joined when the container is created, so that it does not also end
+        // up on the default bridge, and the rest are connected before it starts
+        let (network_mode, networking_config) = match options.networks.split_first() {
+            Some((first, _)) => {
+                let endpoints_config = vec![(first.name.clone(), container_network(first))]
+                    .into_iter()
+                    .collect();
                 (
-                    format!("{}/tcp", config.target),
-                    vec![PortBinding {
-                        host_ip: "0.0.0.0".to_string(),
-                        host_port: format!("{}/tcp", config.host),
-                    }],
+                    Some(first.name.clone()),
+                    Some(NetworkingConfig { endpoints_config }),
                 )
-            })
-            .collect();
+            }
+            None => (options.network_mode.clone(), None),
+        };
 
         let host_config = Some(HostConfig {
             binds: Some(binds),
+            mounts: Some(mounts),
             port_bindings: Some(port_bindings),
+            network_mode,
+            memory: runtime.memory,
+            memory_swap: runtime.memory_swap,
+            nano_cpus: runtime.nano_cpus(),
+            cpu_shares: runtime.cpu_shares,
+            pids_limit: runtime.pids_limit,
+            restart_policy: runtime.restart_policy.map(|policy| RestartPolicy {
+                name: Some(policy.to_string()),
+                maximum_retry_count: runtime.restart_max_retries,
+            }),
+            readonly_rootfs: Some(runtime.read_only_rootfs),
+            cap_add: Some(runtime.cap_add.clone()),
+            cap_drop: Some(runtime.cap_drop.clone()),
             ..Default::default()
         });
 
-        let exposed_ports = options
-            .ports
-            .iter()
-            .map(|config| (format!("{}/tcp", config.target), HashMap::new()))
-            .collect();
-
-        let cmd = options.cmd.iter().map(|s| (*s).to_string()).collect();
+        let cmd: Vec<String> = options.cmd.iter().map(|s| (*s).to_string()).collect();
         let config = Config {
             image: Some(options.image.to_string()),
-            cmd: Some(cmd),
+            // An empty command leaves the image's own in place
+            cmd: if cmd.is_empty() { None } else { Some(cmd) },
+            env: Some(
+                options
+                    .env
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect(),
+            ),
+            healthcheck: options.healthcheck.as_ref().map(health_config),
             exposed_ports: Some(exposed_ports),
             host_config,
+            networking_config,
+            user: runtime.user.clone(),
+            working_dir: runtime.working_dir.clone(),
+            entrypoint: runtime.entrypoint.clone(),
+            labels: Some(options.labels.clone()),
+            stop_signal: options.stop.signal.clone(),
+            stop_timeout: Some(options.stop.timeout as isize),
             ..Default::default()
         };
 
         let res = Docker::create_container(self, c_options, config).await?;
 
+        for network in options.networks.iter().skip(1) {
+            log::debug!("connecting {} to network {}", options.name, network.name);
+            let connect_options = ConnectNetworkOptions {
+                container: res.id.as_str(),
+                endpoint_config: EndpointSettings {
+                    aliases: network.aliases.iter().map(String::as_str).collect(),
+                    ipam_config: endpoint_ipam_config(network),
+                    ..Default::default()
+                },
+            };
+            Docker::connect_network(self, &network.name, connect_options).await?;
+        }
+
         // Start the new container
         Docker::start_container(self, &res.id, None::<StartContainerOptions<String>>).await?;
 
@@ -149,6 +466,7 @@ impl DockerApi for bollard::Docker {
     async fn create_image<'a>(&'a self, options: CreateImageOptions<'a>) -> Result<()> {
         use bollard::image;
 
+        let from_image = format!("{}:{}", options.from_image, options.tag);
         let options = Some(image::CreateImageOptions {
             from_image: options.from_image,
             tag: options.tag,
@@ -157,8 +475,357 @@ impl DockerApi for bollard::Docker {
 
         let mut out_stream = Docker::create_image(self, options, None, None);
         while let Some(msg) = out_stream.next().await {
-            log::debug!("{:?}", msg);
+            match msg {
+                // Progress bars are left out, only each layer's change of status is logged
+                Ok(image::CreateImageResults::CreateImageProgressResponse {
+                    status,
+                    id,
+                    progress: None,
+                    ..
+                }) => match id {
+                    Some(id) => log::debug!("pull: {}: {}", id, status),
+                    None => log::debug!("pull: {}", status),
+                },
+                Ok(image::CreateImageResults::CreateImageProgressResponse { .. }) => {}
+                Ok(image::CreateImageResults::CreateImageError { error, .. }) => {
+                    anyhow::bail!("pulling {}: {}", from_image, error)
+                }
+                Err(e) => return Err(e).with_context(|| format!("pulling {}", from_image)),
+            }
         }
         Ok(())
     }
+    async fn ensure_network(&self, network: &NetworkConfig) -> Result<()> {
+        use bollard::network::{CreateNetworkOptions, IPAMConfig, InspectNetworkOptions, IPAM};
+
+        let res =
+            Docker::inspect_network(self, &network.name, None::<InspectNetworkOptions<&str>>).await;
+        match res {
+            Ok(_) => return Ok(()),
+            Err(e) => match e.kind() {
+                bollard::errors::ErrorKind::DockerResponseNotFoundError { .. } => {}
+                _ => return Err(e.into()),
+            },
+        }
+
+        if !network.create {
+            anyhow::bail!("network {} does not exist", network.name);
+        }
+
+        log::info!("creating network {}", network.name);
+        let ipam = IPAM {
+            config: network
+                .subnet
+                .iter()
+                .map(|subnet| IPAMConfig {
+                    subnet: Some(subnet.as_str()),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let options = CreateNetworkOptions {
+            name: network.name.as_str(),
+            check_duplicate: true,
+            driver: network.driver.as_deref().unwrap_or("bridge"),
+            ipam,
+            ..Default::default()
+        };
+        Docker::create_network(self, options).await?;
+        Ok(())
+    }
+
+    async fn inspect_image(&self, image: &str) -> Result<ImageDetails> {
+        let res = Docker::inspect_image(self, image).await?;
+
+        Ok(ImageDetails {
+            id: res.id,
+            digest: res.repo_digests.into_iter().next(),
+        })
+    }
+
+    fn container_logs(&self, container_name: &str, options: LogsRequest) -> LogStream {
+        use bollard::container::LogsOptions;
+
+        let options = Some(LogsOptions {
+            follow: options.follow,
+            stdout: true,
+            stderr: true,
+            since: options.since.map_or(0, |t| t.timestamp()),
+            tail: options
+                .tail
+                .map_or_else(|| "all".to_string(), |n| n.to_string()),
+            ..Default::default()
+        });
+
+        let stream = Docker::logs(self, container_name, options)
+            .map(|msg| msg.map(|output| output.to_string()).map_err(Into::into));
+        Box::pin(stream)
+    }
+
+    fn build_image(&self, options: BuildOptions, context: Vec<u8>) -> LogStream {
+        use bollard::image::BuildImageResults;
+
+        let stream = Docker::build_image(self, BuildQuery(options), None, Some(context.into()))
+            .filter_map(|msg| match msg {
+                Ok(BuildImageResults::BuildImageStream { stream }) => Some(Ok(stream)),
+                Ok(BuildImageResults::BuildImageError { error, .. }) => {
+                    Some(Err(anyhow::anyhow!("build failed: {}", error)))
+                }
+                Ok(BuildImageResults::BuildImageStatus { status, .. }) => Some(Ok(status + "\n")),
+                Ok(_) => None,
+                Err(e) => Some(Err(e.into())),
+            });
+        Box::pin(stream)
+    }
+}
+
+/// Query parameters for `/build`, written out here as bollard's own options lack `target`
+struct BuildQuery(BuildOptions);
+
+impl BuildImageQueryParams<&'static str> for BuildQuery {
+    fn into_array(
+        self,
+    ) -> std::result::Result<Vec<(&'static str, String)>, bollard::errors::Error> {
+        let options = self.0;
+        let buildargs = serde_json::to_string(&options.args)
+            .map_err(|err| bollard::errors::ErrorKind::JsonSerializeError { err })?;
+
+        let mut query = vec![
+            ("dockerfile", options.dockerfile),
+            ("buildargs", buildargs),
+            ("rm", "true".to_string()),
+            ("forcerm", "true".to_string()),
+        ];
+        query.extend(options.tags.into_iter().map(|tag| ("t", tag)));
+        if let Some(target) = options.target {
+            query.push(("target", target));
+        }
+        Ok(query)
+    }
+}
+
+/// Host bindings for each exposed container port, keyed by docker's `<port>/<protocol>` form
+fn port_bindings(ports: &[PortConfig]) -> HashMap<String, Vec<PortBinding<String>>> {
+    let mut bindings: HashMap<String, Vec<PortBinding<String>>> = HashMap::new();
+
+    for config in ports {
+        let host_ip = config.host_ip.as_deref().unwrap_or("0.0.0.0");
+        for (host, target) in config.pairs() {
+            bindings
+                .entry(format!("{}/{}", target, config.protocol))
+                .or_default()
+                .push(PortBinding {
+                    host_ip: host_ip.to_string(),
+                    host_port: host.to_string(),
+                });
+        }
+    }
+
+    bindings
+}
+
+fn endpoint_ipam_config(network: &NetworkConfig) -> EndpointIPAMConfig<&str> {
+    EndpointIPAMConfig {
+        ipv4_address: network.ipv4_address.as_deref().unwrap_or(""),
+        ipv6_address: network.ipv6_address.as_deref().unwrap_or(""),
+        link_local_ips: Vec::new(),
+    }
+}
+
+fn container_network(network: &NetworkConfig) -> ContainerNetwork {
+    let ipam = endpoint_ipam_config(network);
+
+    ContainerNetwork {
+        ipam_config: Some(EndpointIPAMConfig {
+            ipv4_address: ipam.ipv4_address.to_string(),
+            ipv6_address: ipam.ipv6_address.to_string(),
+            link_local_ips: Vec::new(),
+        }),
+        links: None,
+        aliases: Some(network.aliases.clone()),
+        mac_address: String::new(),
+        global_ipv6_address: String::new(),
+        global_ipv6_prefix_len: 0,
+        ipv6_gateway: String::new(),
+        ip_address: String::new(),
+        ip_prefix_len: 0,
+        gateway: String::new(),
+        endpoint_id: String::new(),
+        network_id: String::new(),
+        driver_opts: None,
+    }
+}
+
+fn health_config(healthcheck: &HealthcheckConfig) -> HealthConfig {
+    // Docker takes these durations in nanoseconds
+    let nanos = |secs: Option<u64>| secs.map(|secs| secs * 1_000_000_000);
+
+    HealthConfig {
+        test: Some(healthcheck.test.clone()),
+        interval: nanos(healthcheck.interval),
+        timeout: nanos(healthcheck.timeout),
+        retries: healthcheck.retries,
+        start_period: nanos(healthcheck.start_period),
+    }
+}
+
+/// Split mounts into `binds` strings and `mounts` specifications
+///
+/// Everything goes through `mounts` apart from bind mounts that need SELinux relabelling, which
+/// the mounts API cannot express.
+fn mounts(configs: &[MountConfig]) -> (Vec<String>, Vec<MountPoint<String>>) {
+    let mut binds = Vec::new();
+    let mut mounts = Vec::new();
+
+    for config in configs {
+        let source = config.host.clone().unwrap_or_default();
+
+        if let Some(label) = config.selinux_label {
+            let mut options = vec![if config.read_only { "ro" } else { "rw" }.to_string()];
+            options.push(match label {
+                SelinuxLabel::Shared => "z".to_string(),
+                SelinuxLabel::Private => "Z".to_string(),
+            });
+            if let Some(propagation) = config.propagation {
+                options.push(propagation.to_string());
+            }
+            binds.push(format!(
+                "{}:{}:{}",
+                source,
+                config.target,
+                options.join(",")
+            ));
+            continue;
+        }
+
+        let mut mount = MountPoint {
+            target: config.target.clone(),
+            source,
+            type_: config.kind.to_string(),
+            read_only: Some(config.read_only),
+            ..Default::default()
+        };
+        match config.kind {
+            MountType::Bind => {
+                mount.bind_options = config.propagation.map(|p| MountPointBindOptions {
+                    propagation: p.to_string(),
+                    non_recursive: false,
+                });
+            }
+            MountType::Tmpfs => {
+                mount.tmpfs_options = Some(MountPointTmpfsOptions {
+                    size_bytes: config.tmpfs_size.unwrap_or(0),
+                    // Validated when the config was loaded
+                    mode: config.tmpfs_mode().unwrap_or(None).unwrap_or(0) as usize,
+                });
+            }
+            MountType::Volume => {}
+        }
+        mounts.push(mount);
+    }
+
+    (binds, mounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(action: &str) -> bollard::system::EventsResults {
+        serde_json::from_value(serde_json::json!({
+            "Type": "container",
+            "Action": action,
+            "Actor": {
+                "ID": "c0ffee",
+                "Attributes": {"name": "foobar", "dockerdeploy.service": "foobar"},
+            },
+            "time": 1_577_836_800,
+            "timeNano": 1_577_836_800_000_000_000i64,
+            "scope": "local",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_container_event() {
+        let die = container_event(event("die")).unwrap();
+        assert_eq!(die.action, ContainerAction::Die);
+        assert_eq!(die.container_id, "c0ffee");
+        assert_eq!(die.name, "foobar");
+        assert_eq!(die.service.as_deref(), Some("foobar"));
+
+        assert_eq!(
+            container_event(event("health_status: unhealthy"))
+                .unwrap()
+                .action,
+            ContainerAction::HealthStatus("unhealthy".to_string())
+        );
+        assert!(container_event(event("start")).is_none());
+    }
+
+    #[test]
+    fn test_mounts() {
+        let configs: Vec<MountConfig> = vec![
+            toml::from_str("host = \"/srv/data\"\ntarget = \"/data\"\nread_only = true\npropagation = \"rslave\"").unwrap(),
+            toml::from_str("host = \"/srv/db\"\ntarget = \"/db\"\nselinux_label = \"private\"").unwrap(),
+            toml::from_str("type = \"volume\"\nhost = \"cache\"\ntarget = \"/cache\"").unwrap(),
+            toml::from_str("type = \"tmpfs\"\ntarget = \"/tmp\"\ntmpfs_size = 1024\ntmpfs_mode = \"1777\"").unwrap(),
+        ];
+
+        let (binds, mounts) = mounts(&configs);
+
+        assert_eq!(binds, vec!["/srv/db:/db:rw,Z"]);
+        assert_eq!(mounts.len(), 3);
+
+        assert_eq!(mounts[0].type_, "bind");
+        assert_eq!(mounts[0].read_only, Some(true));
+        assert_eq!(
+            mounts[0].bind_options.as_ref().unwrap().propagation,
+            "rslave"
+        );
+
+        assert_eq!(mounts[1].type_, "volume");
+        assert_eq!(mounts[1].source, "cache");
+
+        assert_eq!(mounts[2].type_, "tmpfs");
+        let tmpfs = mounts[2].tmpfs_options.unwrap();
+        assert_eq!(tmpfs.size_bytes, 1024);
+        assert_eq!(tmpfs.mode, 0o1777);
+    }
+
+    #[test]
+    fn test_port_bindings() {
+        let ports: Vec<PortConfig> = vec![
+            toml::from_str("host = 5020\ntarget = 80").unwrap(),
+            toml::from_str("host = 5021\ntarget = 80\nhost_ip = \"127.0.0.1\"").unwrap(),
+            toml::from_str("host = \"6000-6001\"\ntarget = \"7000-7001\"\nprotocol = \"udp\"")
+                .unwrap(),
+        ];
+
+        let bindings = port_bindings(&ports);
+
+        let simplify = |key: &str| -> Vec<(String, String)> {
+            bindings[key]
+                .iter()
+                .map(|b| (b.host_ip.clone(), b.host_port.clone()))
+                .collect()
+        };
+        assert_eq!(bindings.len(), 3);
+        assert_eq!(
+            simplify("80/tcp"),
+            vec![
+                ("0.0.0.0".to_string(), "5020".to_string()),
+                ("127.0.0.1".to_string(), "5021".to_string())
+            ]
+        );
+        assert_eq!(
+            simplify("7000/udp"),
+            vec![("0.0.0.0".to_string(), "6000".to_string())]
+        );
+        assert_eq!(
+            simplify("7001/udp"),
+            vec![("0.0.0.0".to_string(), "6001".to_string())]
+        );
+    }
 }
@@ -0,0 +1,42 @@
+//! Docker event subscription
+//!
+//! Forwards lifecycle events of the daemon's labelled containers to the controller, so a dead
+//! container is noticed straight away rather than at the next poll. Polling carries on as a
+//! fallback for anything missed while the subscription is down.
+
+use crate::dockerclient::DockerApi;
+use crate::labels;
+use crate::Message;
+use std::time::Duration;
+use tokio::stream::StreamExt;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// How long to wait before subscribing again after the event stream fails or ends
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+pub(crate) async fn run<D: DockerApi>(tx: UnboundedSender<Message>, docker: D) {
+    log::info!("starting docker event subscription");
+
+    loop {
+        let mut events = docker.container_events(labels::SERVICE);
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(event) => {
+                    log::debug!("docker event: {:?}", event);
+                    tx.send(Message::Container(event))
+                        .expect("sending container event");
+                }
+                Err(e) => {
+                    log::warn!("error reading docker events: {:?}", e);
+                    break;
+                }
+            }
+        }
+
+        log::warn!(
+            "docker event stream ended, resubscribing in {}s",
+            RESUBSCRIBE_DELAY.as_secs()
+        );
+        tokio::time::delay_for(RESUBSCRIBE_DELAY).await;
+    }
+}
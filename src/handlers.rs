@@ -1,28 +1,228 @@
+use crate::activity::Publisher;
+use crate::auth::{self, AuditEntry, Denied};
+use crate::config::Scope;
+use crate::dockerclient::{self, DockerApi, LogsRequest};
 use crate::gitlab::Event;
+use crate::metrics;
+use crate::state::{SharedState, TriggerSource};
 use crate::Message;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Deserialize;
 use std::convert::Infallible;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::stream::StreamExt;
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use warp::http::StatusCode;
 
+/// The dashboard page, which gets everything it shows from the API
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct LogsQuery {
+    pub(crate) tail: Option<u64>,
+    #[serde(default)]
+    pub(crate) follow: bool,
+    /// Unix timestamp or RFC 3339 time
+    pub(crate) since: Option<String>,
+}
+
+fn parse_since(since: &str) -> Option<DateTime<Utc>> {
+    match since.parse::<i64>() {
+        Ok(secs) => Utc.timestamp_opt(secs, 0).single(),
+        Err(_) => DateTime::parse_from_rfc3339(since)
+            .ok()
+            .map(|t| t.with_timezone(&Utc)),
+    }
+}
+
+pub(crate) async fn handle_dashboard() -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::html(DASHBOARD))
+}
+
 pub(crate) async fn handle_trigger(
     tx: UnboundedSender<Message>,
 ) -> Result<impl warp::Reply, Infallible> {
-    tx.send(Message::Trigger).unwrap();
+    tx.send(Message::Trigger(TriggerSource::Api)).unwrap();
 
     Ok(StatusCode::NO_CONTENT)
 }
 
+pub(crate) async fn handle_rollback(
+    tx: UnboundedSender<Message>,
+) -> Result<impl warp::Reply, Infallible> {
+    tx.send(Message::Rollback).unwrap();
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn handle_status(state: SharedState) -> Result<impl warp::Reply, Infallible> {
+    let status = state.read().unwrap().status();
+
+    Ok(warp::reply::json(&status))
+}
+
+pub(crate) async fn handle_history(state: SharedState) -> Result<impl warp::Reply, Infallible> {
+    let state = state.read().unwrap();
+
+    Ok(warp::reply::json(&state.history()))
+}
+
+pub(crate) async fn handle_deployment(
+    id: uuid::Uuid,
+    state: SharedState,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    match state.read().unwrap().deployment(id) {
+        Some(details) => Ok(Box::new(warp::reply::json(&details))),
+        None => Ok(Box::new(StatusCode::NOT_FOUND)),
+    }
+}
+
+pub(crate) async fn handle_audit(state: SharedState) -> Result<impl warp::Reply, Infallible> {
+    let state = state.read().unwrap();
+
+    Ok(warp::reply::json(&state.audit()))
+}
+
+pub(crate) async fn handle_heartbeat(
+    state: SharedState,
+    max_age: chrono::Duration,
+) -> Result<impl warp::Reply, Infallible> {
+    let state = state.read().unwrap();
+    let body = serde_json::json!({
+        "responsive": state.is_responsive(max_age),
+        "busy_since": state.busy_since,
+        "last_heartbeat": state.last_heartbeat,
+    });
+
+    let status = if state.is_alive(max_age) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Ok(warp::reply::with_status(warp::reply::json(&body), status))
+}
+
+pub(crate) async fn handle_metrics() -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::with_header(
+        metrics::render(),
+        "Content-Type",
+        metrics::CONTENT_TYPE,
+    ))
+}
+
+/// Streams the container's logs as chunked plain text, or as server-sent events if the client
+/// accepts `text/event-stream`
+pub(crate) async fn handle_logs<D: DockerApi>(
+    query: LogsQuery,
+    accept: Option<String>,
+    state: SharedState,
+    docker: D,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let container = state.read().unwrap().container.clone();
+
+    let since = match query.since.as_deref().map(parse_since) {
+        Some(None) => return Ok(Box::new(StatusCode::BAD_REQUEST)),
+        Some(since) => since,
+        None => None,
+    };
+    let options = LogsRequest {
+        follow: query.follow,
+        tail: query.tail,
+        since,
+    };
+
+    // Check on the container first so that not finding it is an error response, rather than a
+    // stream that ends straight away. The stream itself may not send anything for a long time.
+    if let Err(e) = docker.inspect_container(&container).await {
+        if dockerclient::is_not_found(&e) {
+            return Ok(Box::new(StatusCode::NOT_FOUND));
+        }
+        log::warn!("error inspecting {} for logs: {:?}", container, e);
+        return Ok(Box::new(StatusCode::BAD_GATEWAY));
+    }
+    let stream = docker.container_logs(&container, options);
+
+    if accept.is_some_and(|accept| accept.contains("text/event-stream")) {
+        // warp needs an event stream that is Sync, which docker's is not, so forward it through a
+        // channel
+        let (tx, rx) = unbounded_channel();
+        tokio::spawn(async move {
+            let mut stream = stream;
+            while let Some(chunk) = stream.next().await {
+                if tx.send(chunk).is_err() {
+                    break;
+                }
+            }
+        });
+        let events = rx.map(|chunk| {
+            let event = match chunk {
+                Ok(text) => (warp::sse::event("log"), warp::sse::data(text)),
+                Err(e) => (
+                    warp::sse::event("error"),
+                    warp::sse::data(format!("{:#}", e)),
+                ),
+            };
+            Ok::<_, Infallible>(event)
+        });
+        return Ok(Box::new(warp::sse::reply(
+            warp::sse::keep_alive().stream(events),
+        )));
+    }
+
+    let response = warp::http::Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(hyper::Body::wrap_stream(stream))
+        .expect("building logs response");
+    Ok(Box::new(response))
+}
+
+/// Streams controller events as server-sent events, named by their type with the event as JSON
+pub(crate) async fn handle_events(activity: Publisher) -> Result<impl warp::Reply, Infallible> {
+    // As with logs, the broadcast receiver is forwarded through a channel to make it Sync
+    let mut events = activity.subscribe();
+    let (tx, rx) = unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (name, data) = match events.recv().await {
+                Ok(event) => (
+                    event.activity.name(),
+                    serde_json::to_value(&event).expect("serializing event"),
+                ),
+                // Tell the client it missed some rather than leaving a silent gap
+                Err(RecvError::Lagged(missed)) => {
+                    ("lagged", serde_json::json!({ "missed": missed }))
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let event = (warp::sse::event(name), warp::sse::json(data));
+            if tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    let events = rx.map(Ok::<_, Infallible>);
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
 pub(crate) async fn handle_webhook(
     header_key: Option<String>,
     event: Event,
     tx: UnboundedSender<Message>,
+    state: SharedState,
     validation_value: Option<String>,
 ) -> Result<impl warp::Reply, Infallible> {
     // Check that the incoming event is a gitlab one and that matches the pipeline event type
     log::debug!("got event {:?}", event);
+    metrics::WEBHOOKS_RECEIVED.inc();
 
     // Should we trigger a pipeline build?
-    let ok = validation_value.map_or(true, |val| header_key.map_or(false, |key| val == key));
+    let ok = match (validation_value, header_key) {
+        (None, _) => true,
+        (Some(val), Some(key)) => auth::secret_matches(&key, &val),
+        (Some(_), None) => false,
+    };
 
     if ok {
         log::info!("expected key matches request, continuing");
@@ -30,27 +230,135 @@ pub(crate) async fn handle_webhook(
             log::debug!("pipeline event configured to run new deploy");
             if pipeline.should_rerun_pipeline() {
                 log::info!("webhook trigger accepted");
-                tx.send(Message::Trigger).unwrap();
+                metrics::WEBHOOKS_ACCEPTED.inc();
+                audit_webhook(&state, None);
+                tx.send(Message::Trigger(TriggerSource::Webhook)).unwrap();
             } else {
                 log::info!("webhook trigger rejected");
+                metrics::WEBHOOKS_REJECTED
+                    .with_label_values(&["pipeline_not_deployable"])
+                    .inc();
             }
         } else {
             log::debug!("{:?} event _not_ configured to run new deploy", event);
+            metrics::WEBHOOKS_REJECTED
+                .with_label_values(&["not_pipeline_event"])
+                .inc();
         }
 
         Ok(StatusCode::NO_CONTENT)
     } else {
+        metrics::WEBHOOKS_REJECTED
+            .with_label_values(&["unauthorized"])
+            .inc();
+        audit_webhook(&state, Some("invalid validation key"));
         Ok(StatusCode::UNAUTHORIZED)
     }
 }
 
+/// Webhooks are checked against `validation_key` rather than a token, so they are audited here
+fn audit_webhook(state: &SharedState, refused: Option<&str>) {
+    auth::audit(
+        state,
+        AuditEntry {
+            time: Utc::now(),
+            token: None,
+            action: "POST /webhook".to_string(),
+            scope: Scope::Deploy,
+            allowed: refused.is_none(),
+            reason: refused.map(str::to_string),
+        },
+    );
+}
+
+/// Turns refused requests into 401 and 403 responses, leaving other rejections to warp
+pub(crate) async fn handle_rejection(
+    rejection: warp::Rejection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match rejection.find::<Denied>() {
+        Some(denied) => {
+            let body = serde_json::json!({ "error": denied.to_string() });
+            let reply = warp::reply::with_status(warp::reply::json(&body), denied.status());
+            Ok(warp::reply::with_header(
+                reply,
+                "WWW-Authenticate",
+                "Bearer",
+            ))
+        }
+        None => Err(rejection),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::gitlab::{Build, Event, ObjectAttributes, Pipeline, Status};
-    use tokio::sync::mpsc::unbounded_channel;
+    use crate::state::State;
+    use chrono::Duration;
     use warp::reply::Reply;
 
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(
+            parse_since("1577836800"),
+            Some(Utc.timestamp(1_577_836_800, 0))
+        );
+        assert_eq!(
+            parse_since("2020-01-01T01:00:00+01:00"),
+            Some(Utc.timestamp(1_577_836_800, 0))
+        );
+        assert_eq!(parse_since("yesterday"), None);
+        assert_eq!(parse_since(&i64::MAX.to_string()), None);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_responsive() {
+        let state = State::default().shared();
+        state.write().unwrap().last_heartbeat = Some(Utc::now());
+
+        let res = handle_heartbeat(state, Duration::seconds(30))
+            .await
+            .unwrap();
+
+        let response = res.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_unresponsive() {
+        let state = State::default().shared();
+        state.write().unwrap().last_heartbeat = Some(Utc::now() - Duration::seconds(60));
+
+        let res = handle_heartbeat(state, Duration::seconds(30))
+            .await
+            .unwrap();
+
+        let response = res.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_dashboard() {
+        let response = handle_dashboard().await.unwrap().into_response();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+    }
+
+    #[tokio::test]
+    async fn test_deployment_not_found() {
+        let state = State::default().shared();
+
+        let res = handle_deployment(uuid::Uuid::new_v4(), state)
+            .await
+            .unwrap();
+
+        assert_eq!(res.into_response().status(), StatusCode::NOT_FOUND);
+    }
+
     // Tests for header validation
     #[tokio::test]
     async fn test_webhook_happy_path() {
@@ -63,9 +371,10 @@ mod tests {
         });
         // TODO: check response from channel
         let (tx, _rx) = unbounded_channel();
+        let state = State::new("foo", "foo:latest").shared();
         let validation_value = Some("abc".to_string());
 
-        let res = handle_webhook(header_key, event, tx, validation_value)
+        let res = handle_webhook(header_key, event, tx, state.clone(), validation_value)
             .await
             .unwrap();
 
@@ -84,14 +393,25 @@ mod tests {
         });
         // TODO: check response from channel
         let (tx, _rx) = unbounded_channel();
+        let mut state = State::new("foo", "foo:latest");
+        state.auth = Some(
+            toml::from_str(
+                "[[tokens]]\nname = \"ci\"\ntoken = \"0123456789abcdef\"\nscopes = [\"deploy\"]",
+            )
+            .unwrap(),
+        );
+        let state = state.shared();
         let validation_value = Some("abc".to_string());
 
-        let res = handle_webhook(header_key, event, tx, validation_value)
+        let res = handle_webhook(header_key, event, tx, state.clone(), validation_value)
             .await
             .unwrap();
 
         let response = res.into_response();
         assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let state = state.read().unwrap();
+        assert_eq!(state.audit()[0].action, "POST /webhook");
+        assert!(!state.audit()[0].allowed);
     }
 
     #[tokio::test]
@@ -105,9 +425,10 @@ mod tests {
         });
         // TODO: check response from channel
         let (tx, _rx) = unbounded_channel();
+        let state = State::new("foo", "foo:latest").shared();
         let validation_value = Some("abc".to_string());
 
-        let res = handle_webhook(header_key, event, tx, validation_value)
+        let res = handle_webhook(header_key, event, tx, state.clone(), validation_value)
             .await
             .unwrap();
 
@@ -126,9 +447,10 @@ mod tests {
         });
         // TODO: check response from channel
         let (tx, _rx) = unbounded_channel();
+        let state = State::new("foo", "foo:latest").shared();
         let validation_value = None;
 
-        let res = handle_webhook(header_key, event, tx, validation_value)
+        let res = handle_webhook(header_key, event, tx, state.clone(), validation_value)
             .await
             .unwrap();
 
@@ -147,9 +469,10 @@ mod tests {
         });
         // TODO: check response from channel
         let (tx, _rx) = unbounded_channel();
+        let state = State::new("foo", "foo:latest").shared();
         let validation_value = None;
 
-        let res = handle_webhook(header_key, event, tx, validation_value)
+        let res = handle_webhook(header_key, event, tx, state.clone(), validation_value)
             .await
             .unwrap();
 
@@ -172,7 +495,15 @@ mod tests {
         let (tx, mut rx) = unbounded_channel();
 
         tokio::spawn(async move {
-            let res = handle_webhook(None, event, tx, None).await.unwrap();
+            let res = handle_webhook(
+                None,
+                event,
+                tx,
+                State::new("foo", "foo:latest").shared(),
+                None,
+            )
+            .await
+            .unwrap();
 
             let response = res.into_response();
             assert_eq!(response.status(), StatusCode::NO_CONTENT);
@@ -180,7 +511,7 @@ mod tests {
 
         match rx.recv().await {
             // Not trigger message
-            Some(msg) => assert_eq!(msg, Message::Trigger),
+            Some(msg) => assert_eq!(msg, Message::Trigger(TriggerSource::Webhook)),
             None => unreachable!("sender dropped"),
         }
     }
@@ -200,7 +531,15 @@ mod tests {
 
         let tx2 = tx.clone();
         tokio::spawn(async move {
-            let res = handle_webhook(None, event, tx2, None).await.unwrap();
+            let res = handle_webhook(
+                None,
+                event,
+                tx2,
+                State::new("foo", "foo:latest").shared(),
+                None,
+            )
+            .await
+            .unwrap();
 
             let response = res.into_response();
             assert_eq!(response.status(), StatusCode::NO_CONTENT);
@@ -211,7 +550,7 @@ mod tests {
 
         match rx.recv().await {
             // Not trigger message
-            Some(msg) => assert!(msg != Message::Trigger),
+            Some(msg) => assert!(msg != Message::Trigger(TriggerSource::Webhook)),
             None => unreachable!("sender dropped"),
         }
     }
@@ -236,7 +575,15 @@ mod tests {
 
         let tx2 = tx.clone();
         tokio::spawn(async move {
-            let res = handle_webhook(None, event, tx2, None).await.unwrap();
+            let res = handle_webhook(
+                None,
+                event,
+                tx2,
+                State::new("foo", "foo:latest").shared(),
+                None,
+            )
+            .await
+            .unwrap();
 
             let response = res.into_response();
             assert_eq!(response.status(), StatusCode::NO_CONTENT);
@@ -247,7 +594,7 @@ mod tests {
 
         match rx.recv().await {
             // Not trigger message
-            Some(msg) => assert!(msg != Message::Trigger),
+            Some(msg) => assert!(msg != Message::Trigger(TriggerSource::Webhook)),
             None => unreachable!("sender dropped"),
         }
     }
@@ -265,7 +612,15 @@ mod tests {
 
         let tx2 = tx.clone();
         tokio::spawn(async move {
-            let res = handle_webhook(None, event, tx2, None).await.unwrap();
+            let res = handle_webhook(
+                None,
+                event,
+                tx2,
+                State::new("foo", "foo:latest").shared(),
+                None,
+            )
+            .await
+            .unwrap();
 
             let response = res.into_response();
             assert_eq!(response.status(), StatusCode::NO_CONTENT);
@@ -276,7 +631,7 @@ mod tests {
 
         match rx.recv().await {
             // Not trigger message
-            Some(msg) => assert!(msg != Message::Trigger),
+            Some(msg) => assert!(msg != Message::Trigger(TriggerSource::Webhook)),
             None => unreachable!("sender dropped"),
         }
     }
@@ -0,0 +1,59 @@
+//! Liveness heartbeats
+//!
+//! Every `sleep_time` seconds a heartbeat message is queued for the controller, which stamps the
+//! shared state when it gets to it. A stale stamp means the event loop is stuck or gone, which is
+//! what the liveness endpoint reports and what stops the outbound heartbeats. The exception is a
+//! deployment, which holds up the loop for as long as it runs: the controller reports itself busy
+//! then, and counts as alive.
+
+use crate::config::HeartbeatConfig;
+use crate::state::SharedState;
+use crate::Message;
+use chrono::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// How many heartbeats the controller may miss before it is considered unresponsive
+const MISSED_HEARTBEATS: u64 = 3;
+
+/// Oldest a heartbeat can be for the controller to still count as responsive
+pub(crate) fn max_age(config: &HeartbeatConfig) -> Duration {
+    Duration::seconds((config.sleep_time * MISSED_HEARTBEATS) as i64)
+}
+
+pub(crate) async fn run(tx: UnboundedSender<Message>, state: SharedState, config: HeartbeatConfig) {
+    log::info!("starting heartbeat loop");
+
+    let client = reqwest::Client::new();
+    let interval = std::time::Duration::from_secs(config.sleep_time);
+    let max_age = max_age(&config);
+
+    loop {
+        tx.send(Message::Heartbeat)
+            .expect("sending heartbeat message");
+        tokio::time::delay_for(interval).await;
+
+        if let Some(url) = &config.url {
+            let alive = state.read().unwrap().is_alive(max_age);
+            if alive {
+                send(&client, url).await;
+            } else {
+                log::warn!(
+                    "controller is unresponsive, not sending heartbeat to {}",
+                    url
+                );
+            }
+        }
+    }
+}
+
+async fn send(client: &reqwest::Client, url: &str) {
+    log::debug!("sending heartbeat to {}", url);
+
+    match client.get(url).send().await {
+        Ok(res) if !res.status().is_success() => {
+            log::warn!("heartbeat to {} returned {}", url, res.status())
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("error sending heartbeat to {}: {:?}", url, e),
+    }
+}
@@ -0,0 +1,231 @@
+//! Pre-deploy and post-deploy hooks
+//!
+//! A hook either runs a command in a one-off container of the image being deployed, with the
+//! service's mounts, networks and runtime settings, or makes an HTTP request. A hook that fails or
+//! times out produces a [`HookError`], which the controller uses to decide between aborting and
+//! rolling back.
+
+use crate::config::{ContainerConfig, HookConfig, NetworkConfig, RestartPolicy};
+use crate::dockerclient::{DockerApi, LogsRequest, RunContainerOptions};
+use crate::labels;
+use crate::state::Deployment;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use tokio::stream::StreamExt;
+
+/// Label put on hook containers in place of the service label, so they are never mistaken for the
+/// service itself
+const HOOK_LABEL: &str = "dockerdeploy.hook-for";
+
+/// How many lines of a failed hook container's output to include in the error
+const FAILED_LOG_LINES: u64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HookStage {
+    PreDeploy,
+    PostDeploy,
+}
+
+impl fmt::Display for HookStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            HookStage::PreDeploy => "pre-deploy",
+            HookStage::PostDeploy => "post-deploy",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Attached as context to the error of a failed hook
+#[derive(Debug)]
+pub(crate) struct HookError {
+    pub(crate) stage: HookStage,
+    pub(crate) name: String,
+}
+
+impl fmt::Display for HookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} hook `{}` failed", self.stage, self.name)
+    }
+}
+
+/// Run every hook for a stage in order, stopping at the first failure
+pub(crate) async fn run_all<D: DockerApi>(
+    docker: &D,
+    stage: HookStage,
+    hooks: &[HookConfig],
+    container: &ContainerConfig,
+    deployment: &Deployment,
+) -> Result<()> {
+    for hook in hooks {
+        log::info!("running {} hook {}", stage, hook.name);
+
+        let timeout = Duration::from_secs(hook.timeout);
+        let res =
+            match tokio::time::timeout(timeout, run(docker, stage, hook, container, deployment))
+                .await
+            {
+                Ok(res) => res,
+                Err(_) => {
+                    // Dropping the future only stops the wait, so the container is still running
+                    if hook.command.is_some() {
+                        let name = container_name(&container.name, stage, &hook.name);
+                        if let Err(e) = docker.remove_container(&name).await {
+                            log::warn!("error removing hook container {}: {:?}", name, e);
+                        }
+                    }
+                    Err(anyhow::anyhow!("timed out after {}s", hook.timeout))
+                }
+            };
+
+        res.context(HookError {
+            stage,
+            name: hook.name.clone(),
+        })?;
+    }
+    Ok(())
+}
+
+async fn run<D: DockerApi>(
+    docker: &D,
+    stage: HookStage,
+    hook: &HookConfig,
+    container: &ContainerConfig,
+    deployment: &Deployment,
+) -> Result<()> {
+    if let Some(url) = &hook.http {
+        reqwest::get(url)
+            .await
+            .with_context(|| format!("requesting {}", url))?
+            .error_for_status()?;
+    }
+    if let Some(command) = &hook.command {
+        run_container(docker, stage, hook, command, container, deployment).await?;
+    }
+    Ok(())
+}
+
+async fn run_container<D: DockerApi>(
+    docker: &D,
+    stage: HookStage,
+    hook: &HookConfig,
+    command: &[String],
+    container: &ContainerConfig,
+    deployment: &Deployment,
+) -> Result<()> {
+    let name = container_name(&container.name, stage, &hook.name);
+    // Clear out a container left behind by an earlier run that was interrupted
+    docker.remove_container(&name).await?;
+
+    // A one-off command must not be restarted when it exits
+    let mut runtime = container.runtime.clone();
+    runtime.restart_policy = Some(RestartPolicy::No);
+    runtime.restart_max_retries = None;
+
+    let mut hook_labels: HashMap<String, String> = container
+        .labels
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    hook_labels.insert(HOOK_LABEL.to_string(), container.name.clone());
+    hook_labels.insert(labels::DEPLOY_ID.to_string(), deployment.id.to_string());
+
+    docker
+        .run_container(RunContainerOptions {
+            name: &name,
+            image: &deployment.image,
+            cmd: command.iter().map(String::as_str).collect(),
+            // Ports are left out as they would clash with the running service
+            ports: Vec::new(),
+            mounts: container.mounts.clone(),
+            // The service's addresses and aliases belong to the service
+            networks: container
+                .networks
+                .iter()
+                .map(NetworkConfig::anonymous)
+                .collect(),
+            network_mode: container.network_mode.clone(),
+            runtime,
+            labels: hook_labels,
+            env: container.env.clone(),
+            // The hook is done when its command exits, so there is nothing to check
+            healthcheck: None,
+            stop: container.stop.clone(),
+        })
+        .await
+        .context("starting hook container")?;
+
+    let code = docker.wait_container(&name).await;
+    let res = match code {
+        Ok(0) => Ok(()),
+        Ok(code) => {
+            let output = last_output(docker, &name).await;
+            Err(anyhow::anyhow!(
+                "{:?} exited with status {}\n{}",
+                command,
+                code,
+                output
+            ))
+        }
+        Err(e) => Err(e.context("waiting for hook container")),
+    };
+
+    if let Err(e) = docker.remove_container(&name).await {
+        log::warn!("error removing hook container {}: {:?}", name, e);
+    }
+    res
+}
+
+async fn last_output<D: DockerApi>(docker: &D, name: &str) -> String {
+    let options = LogsRequest {
+        tail: Some(FAILED_LOG_LINES),
+        ..Default::default()
+    };
+    let mut stream = docker.container_logs(name, options);
+    let mut output = String::new();
+    while let Some(Ok(chunk)) = stream.next().await {
+        output.push_str(&chunk);
+    }
+    output.trim_end().to_string()
+}
+
+/// e.g. `foobar-pre-deploy-migrate`, with anything docker does not allow in a name replaced
+fn container_name(service: &str, stage: HookStage, hook: &str) -> String {
+    format!("{}-{}-{}", service, stage, hook)
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' | '-' => c,
+            _ => '-',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_container_name() {
+        assert_eq!(
+            container_name("foobar", HookStage::PreDeploy, "run migrations"),
+            "foobar-pre-deploy-run-migrations"
+        );
+    }
+
+    #[test]
+    fn test_hook_error_downcast() {
+        let res: Result<()> = Err(anyhow::anyhow!("exited with status 1")).context(HookError {
+            stage: HookStage::PostDeploy,
+            name: "smoke".to_string(),
+        });
+        let e = res.unwrap_err();
+
+        assert_eq!(e.to_string(), "post-deploy hook `smoke` failed");
+        assert_eq!(
+            e.downcast_ref::<HookError>().map(|h| h.stage),
+            Some(HookStage::PostDeploy)
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Labels put on every container the daemon creates
+//!
+//! They are how the daemon tells its own containers apart from anything else on the host, and
+//! record which deployment and configuration each container came from.
+
+use crate::dockerclient::ContainerSummary;
+use crate::state::Deployment;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Prefix reserved for the daemon's own labels
+pub(crate) const PREFIX: &str = "dockerdeploy.";
+
+pub(crate) const SERVICE: &str = "dockerdeploy.service";
+pub(crate) const CONFIG_HASH: &str = "dockerdeploy.config-hash";
+pub(crate) const IMAGE_DIGEST: &str = "dockerdeploy.image-digest";
+pub(crate) const DEPLOY_ID: &str = "dockerdeploy.deploy-id";
+pub(crate) const TRIGGER: &str = "dockerdeploy.trigger";
+/// Put on sidecars in place of the service label, naming the service they belong to
+pub(crate) const SIDECAR_OF: &str = "dockerdeploy.sidecar-of";
+
+/// Labels for a container started by `deployment`, on top of the user's own
+pub(crate) fn for_deployment(
+    service: &str,
+    config_hash: &str,
+    deployment: &Deployment,
+    user_labels: &BTreeMap<String, String>,
+) -> HashMap<String, String> {
+    let mut labels: HashMap<String, String> = user_labels
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+
+    labels.insert(SERVICE.to_string(), service.to_string());
+    labels.insert(CONFIG_HASH.to_string(), config_hash.to_string());
+    labels.insert(DEPLOY_ID.to_string(), deployment.id.to_string());
+    labels.insert(TRIGGER.to_string(), deployment.trigger.to_string());
+    if let Some(digest) = &deployment.image_digest {
+        labels.insert(IMAGE_DIGEST.to_string(), digest.clone());
+    }
+
+    labels
+}
+
+/// A container carrying the daemon's labels, as reported by `/status`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct ManagedContainer {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) service: String,
+    pub(crate) running: bool,
+    pub(crate) config_hash: Option<String>,
+    pub(crate) image_digest: Option<String>,
+    pub(crate) deploy_id: Option<String>,
+    pub(crate) trigger: Option<String>,
+    /// The daemon starts its containers as soon as it creates them, so this is when the container
+    /// was first started
+    #[serde(default)]
+    pub(crate) created: Option<DateTime<Utc>>,
+}
+
+impl ManagedContainer {
+    /// `None` for containers without a service label, which the daemon did not create
+    pub(crate) fn from_summary(summary: &ContainerSummary) -> Option<Self> {
+        let label = |key: &str| summary.labels.get(key).cloned();
+
+        Some(ManagedContainer {
+            id: summary.id.clone(),
+            name: summary.name.clone(),
+            service: label(SERVICE)?,
+            running: summary.running,
+            config_hash: label(CONFIG_HASH),
+            image_digest: label(IMAGE_DIGEST),
+            deploy_id: label(DEPLOY_ID),
+            trigger: label(TRIGGER),
+            created: summary.created,
+        })
+    }
+
+    /// A container from before the daemon labelled its containers, which has exactly the service's
+    /// name and none of the daemon's labels. It is counted as the service's until a deploy
+    /// replaces it, so upgrading the daemon does not mean removing the container by hand.
+    pub(crate) fn legacy(summary: &ContainerSummary, service: &str) -> Option<Self> {
+        if !is_legacy(summary, service) {
+            return None;
+        }
+
+        Some(ManagedContainer {
+            id: summary.id.clone(),
+            name: summary.name.clone(),
+            service: service.to_string(),
+            running: summary.running,
+            config_hash: None,
+            image_digest: None,
+            deploy_id: None,
+            trigger: None,
+            created: summary.created,
+        })
+    }
+}
+
+/// See `ManagedContainer::legacy`
+pub(crate) fn is_legacy(summary: &ContainerSummary, service: &str) -> bool {
+    summary.name == service && !summary.labels.keys().any(|k| k.starts_with(PREFIX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::TriggerSource;
+
+    #[test]
+    fn test_round_trip() {
+        let mut deployment = Deployment::new(TriggerSource::Webhook, "foo:latest");
+        deployment.image_digest = Some("foo@sha256:abc".to_string());
+        let user_labels = vec![("team".to_string(), "web".to_string())]
+            .into_iter()
+            .collect();
+
+        let labels = for_deployment("foo", "1234", &deployment, &user_labels);
+        assert_eq!(labels["team"], "web");
+
+        let summary = ContainerSummary {
+            id: "c0ffee".to_string(),
+            name: "foo".to_string(),
+            running: true,
+            labels,
+            created: None,
+        };
+        let managed = ManagedContainer::from_summary(&summary).unwrap();
+
+        assert_eq!(managed.service, "foo");
+        assert_eq!(managed.config_hash.as_deref(), Some("1234"));
+        assert_eq!(managed.image_digest.as_deref(), Some("foo@sha256:abc"));
+        assert_eq!(managed.deploy_id, Some(deployment.id.to_string()));
+        assert_eq!(managed.trigger.as_deref(), Some("webhook"));
+    }
+
+    #[test]
+    fn test_unlabelled_container() {
+        let summary = ContainerSummary {
+            id: "c0ffee".to_string(),
+            name: "foo".to_string(),
+            running: true,
+            labels: HashMap::new(),
+            created: None,
+        };
+
+        assert!(ManagedContainer::from_summary(&summary).is_none());
+
+        let legacy = ManagedContainer::legacy(&summary, "foo").unwrap();
+        assert_eq!(legacy.service, "foo");
+        assert_eq!(legacy.config_hash, None);
+        assert!(ManagedContainer::legacy(&summary, "bar").is_none());
+    }
+}
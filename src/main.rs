@@ -1,25 +1,56 @@
 use anyhow::{Context, Result};
 use bollard::Docker;
+use chrono::Utc;
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use serde::Deserialize;
-use std::net::IpAddr;
 use std::path::PathBuf;
 use structopt::StructOpt;
+use tokio::stream::StreamExt;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use warp::Filter;
 
+mod activity;
+mod auth;
+mod build;
+mod canary;
+mod client;
+mod compose;
 mod config;
+mod deploylog;
 mod dockerclient;
+mod events;
 mod gitlab;
 mod handlers;
+mod heartbeat;
+mod hooks;
+mod labels;
+mod metrics;
+mod notifications;
+mod readiness;
+mod replicas;
 mod routes;
+mod sidecars;
+mod state;
 
-use dockerclient::DockerApi;
+use activity::{Activity, Publisher};
+use config::{NetworkConfig, PortConfig, PreStopConfig};
+use deploylog::DeployLog;
+use dockerclient::{
+    ContainerAction, ContainerEvent, ContainerFilter, ContainerSummary, DockerApi, LogsRequest,
+};
+use hooks::{HookError, HookStage};
+use labels::ManagedContainer;
+use notifications::{Notification, NotificationKind, Notifiers};
+use replicas::Replica;
+use state::{Busy, CrashReport, Deployment, Outcome, SharedState, State, TriggerSource};
 
 #[derive(Debug, Clone, Deserialize, PartialEq)]
 enum Message {
     Poll,
-    Trigger,
+    Trigger(TriggerSource),
+    Rollback,
+    Heartbeat,
+    Container(ContainerEvent),
     Reload(notify::event::Event),
     Debug,
 }
@@ -30,6 +61,18 @@ struct Controller<D> {
     docker: D,
     cfg: config::DockerDeployConfig,
     cfg_file: PathBuf,
+    state: SharedState,
+    notifiers: Notifiers,
+    activity: Publisher,
+    /// Why the container died, kept for the deployment the poll loop triggers to replace it
+    pending_crash: Option<CrashReport>,
+    /// Set between the poll loop asking for a restart and the restart starting, so that a poll
+    /// in between does not ask again
+    restart_pending: bool,
+    /// Watches the config file, and the compose file it names, to reload the config
+    watcher: Option<RecommendedWatcher>,
+    /// The compose file `watcher` is watching
+    watched_compose: Option<String>,
 }
 
 impl<D: DockerApi> Controller<D> {
@@ -43,93 +86,605 @@ impl<D: DockerApi> Controller<D> {
             config::DockerDeployConfig::from_file(&cfg_file).context("reading config file")?;
         log::debug!("got config {:?}", config);
 
+        let mut state = State::new(&config.container.name, config.image.reference());
+        state.config_hash = config.container.hash.clone();
+        state.auth = config.auth.clone();
+        if config.auth.is_none() {
+            log::warn!(
+                "no [auth] section in the config, the API is open to anyone who can reach it"
+            );
+        }
+        let state = state.shared();
+        let notifiers = Notifiers::from_config(config.notifications.as_ref());
+
         Ok(Controller {
             tx,
             rx,
             docker,
             cfg: config,
             cfg_file,
+            state,
+            notifiers,
+            activity: Publisher::new(),
+            pending_crash: None,
+            restart_pending: false,
+            watcher: None,
+            watched_compose: None,
         })
     }
 
+    /// Take over the config file's watcher, and add the compose file to it
+    fn set_watcher(&mut self, watcher: RecommendedWatcher) {
+        self.watcher = Some(watcher);
+        self.watch_compose();
+    }
+
+    /// Watch the compose file the config names, as it is read as part of the config, so changes to
+    /// it reload the config too. Follows the config when a reload adds, moves or drops it.
+    fn watch_compose(&mut self) {
+        let file = self
+            .cfg
+            .compose
+            .as_ref()
+            .map(|compose| compose.file.clone());
+        let watcher = match &mut self.watcher {
+            Some(watcher) => watcher,
+            None => return,
+        };
+        if file == self.watched_compose {
+            return;
+        }
+
+        if let Some(old) = &self.watched_compose {
+            if let Err(e) = watcher.unwatch(old) {
+                log::warn!("error unwatching compose file {}: {:?}", old, e);
+            }
+        }
+        self.watched_compose = None;
+        if let Some(new) = file {
+            // Left unrecorded on failure, so the next reload tries again
+            match watcher.watch(&new, RecursiveMode::NonRecursive) {
+                Ok(()) => self.watched_compose = Some(new),
+                Err(e) => log::warn!("error watching compose file {}: {:?}", new, e),
+            }
+        }
+    }
+
     async fn event_loop(&mut self) {
         while let Some(msg) = self.rx.recv().await {
             match msg {
-                Message::Trigger => match self.trigger_refresh().await {
-                    Ok(_) => {}
-                    Err(e) => log::warn!("error in handler: {:?}", e),
-                },
-                Message::Poll => {
-                    log::debug!("checking on container");
-
-                    match self
-                        .docker
-                        .is_container_running(&self.cfg.container.name)
-                        .await
-                    {
-                        Ok(r) => {
-                            if !r {
-                                log::info!("configured container not running, starting");
-                                // Trigger a refresh
-                                self.tx
-                                    .send(Message::Trigger)
-                                    .expect("sending trigger request");
-                            } else {
-                                log::info!(
-                                    "found configured container `{}`",
-                                    self.cfg.container.name
-                                )
-                            }
-                        }
-                        Err(e) => match e.downcast_ref::<bollard::errors::Error>() {
-                            Some(e) => match e.kind() {
-                                bollard::errors::ErrorKind::DockerResponseNotFoundError {
-                                    ..
-                                } => {
-                                    log::info!("configured container not running, starting");
-                                    // Trigger a refresh
-                                    self.tx
-                                        .send(Message::Trigger)
-                                        .expect("sending trigger request");
-                                }
-                                _ => log::warn!(
-                                    "error inspecting container {}: {:?}",
-                                    self.cfg.container.name,
-                                    e
-                                ),
-                            },
-                            None => log::warn!(
-                                "error inspecting container {}: {:?}",
-                                self.cfg.container.name,
-                                e
-                            ),
-                        },
-                    }
+                Message::Trigger(source) => {
+                    self.activity
+                        .publish(Activity::TriggerReceived { trigger: source });
+                    self.deploy(source).await
+                }
+                Message::Rollback => {
+                    self.activity.publish(Activity::TriggerReceived {
+                        trigger: TriggerSource::Rollback,
+                    });
+                    self.deploy(TriggerSource::Rollback).await
                 }
+                Message::Poll => self.poll().await,
                 Message::Reload(event) => {
                     use notify::event::EventKind;
 
                     log::trace!("reload event: {:?}", event);
                     if let EventKind::Modify(_) = event.kind {
                         log::info!("reloading config");
-                        let new_config = config::DockerDeployConfig::from_file(&self.cfg_file)
-                            .expect("reading config file");
-                        self.cfg = new_config;
+                        // A bad edit, to the config or the compose file, must not stop the daemon
+                        self.cfg = match config::DockerDeployConfig::from_file(&self.cfg_file) {
+                            Ok(config) => config,
+                            Err(e) => {
+                                log::error!(
+                                    "error reloading config, keeping the current one: {:#}",
+                                    e
+                                );
+                                continue;
+                            }
+                        };
+                        self.notifiers = Notifiers::from_config(self.cfg.notifications.as_ref());
+                        self.watch_compose();
+                        let container = self.cfg.container.name.clone();
+                        let image = self.cfg.image.reference();
+                        let config_hash = self.cfg.container.hash.clone();
+                        let auth = self.cfg.auth.clone();
+                        self.activity.publish(Activity::ConfigReloaded {
+                            container: container.clone(),
+                            image: image.clone(),
+                        });
+                        self.update_state(|state| {
+                            state.container = container;
+                            state.image = image;
+                            state.config_hash = config_hash;
+                            state.auth = auth;
+                        });
                         log::info!("config reloaded: {:?}", self.cfg);
                     }
                 }
+                Message::Heartbeat => {
+                    log::trace!("heartbeat");
+                    self.update_state(|state| state.last_heartbeat = Some(Utc::now()));
+                }
+                Message::Container(event) => self.handle_container_event(event).await,
                 Message::Debug => {}
             }
         }
     }
 
-    async fn trigger_refresh(&mut self) -> Result<()> {
-        self.pull_image().await?;
-        self.stop_running_contianer().await?;
-        self.run_container().await?;
+    /// Check on the labelled containers, restarting ours if it is not running
+    async fn poll(&mut self) {
+        log::debug!("checking on container");
+
+        let containers = match self
+            .docker
+            .list_containers(ContainerFilter::Label(labels::SERVICE))
+            .await
+        {
+            Ok(containers) => containers,
+            Err(e) => {
+                log::warn!("error listing containers: {:?}", e);
+                return;
+            }
+        };
+        let mut containers: Vec<ManagedContainer> = containers
+            .iter()
+            .filter_map(ManagedContainer::from_summary)
+            .collect();
+        let name = &self.cfg.container.name;
+        match self
+            .docker
+            .list_containers(ContainerFilter::Name(name))
+            .await
+        {
+            Ok(named) => containers.extend(
+                named
+                    .iter()
+                    .filter_map(|c| ManagedContainer::legacy(c, name)),
+            ),
+            Err(e) => {
+                log::warn!("error looking for container {}: {:?}", name, e);
+                return;
+            }
+        }
+
+        let orphans: Vec<ManagedContainer> = containers
+            .iter()
+            .filter(|c| &c.service != name)
+            .cloned()
+            .collect();
+        for orphan in orphans {
+            if !self.cfg.remove_orphans {
+                log::debug!(
+                    "found container {} for service {}",
+                    orphan.name,
+                    orphan.service
+                );
+                continue;
+            }
+            log::info!(
+                "removing orphan container {} for service {}",
+                orphan.name,
+                orphan.service
+            );
+            match self.docker.remove_container(&orphan.id).await {
+                Ok(()) => containers.retain(|c| c.id != orphan.id),
+                Err(e) => log::warn!("error removing orphan container {}: {:?}", orphan.name, e),
+            }
+        }
+
+        if let Err(e) = self.ensure_sidecars(true).await {
+            log::warn!("error restarting sidecars: {:?}", e);
+        }
+
+        let replicas = match replicas::plan(&self.cfg.container) {
+            Ok(replicas) => replicas,
+            Err(e) => {
+                log::warn!("error planning replicas: {:?}", e);
+                return;
+            }
+        };
+        let find = |replica: &Replica| {
+            containers
+                .iter()
+                .find(|c| &c.service == name && c.name == replica.name)
+        };
+        let down: Vec<&Replica> = replicas
+            .iter()
+            .filter(|r| !find(r).is_some_and(|c| c.running))
+            .collect();
+        let running = down.is_empty();
+        // The first dead replica that is still around, to report on
+        let dead = down.iter().find_map(|r| find(r)).cloned();
+
+        self.update_state(|state| {
+            state.running = running;
+            state.last_checked = Some(Utc::now());
+            state.containers = containers;
+        });
+        metrics::CONTAINER_UP.set(running as i64);
+        self.activity.publish(Activity::PollResult {
+            running,
+            down: down.iter().map(|r| r.name.clone()).collect(),
+        });
+
+        if running {
+            log::info!("found configured container `{}`", name);
+        } else if self.restart_pending {
+            log::debug!("configured container not running, restart already requested");
+        } else {
+            log::info!(
+                "{} of {} replicas of `{}` not running, starting",
+                down.len(),
+                replicas.len(),
+                name
+            );
+            metrics::POLL_RESTARTS.inc();
+            // The dead container is only removed by the deploy, so it can still be inspected
+            self.pending_crash = match dead {
+                Some(dead) => self.crash_report(&dead.id).await,
+                None => None,
+            };
+            self.notify_restart();
+            // Trigger a refresh
+            self.restart_pending = true;
+            self.tx
+                .send(Message::Trigger(TriggerSource::Poll))
+                .expect("sending trigger request");
+        }
+    }
+
+    async fn handle_container_event(&mut self, event: ContainerEvent) {
+        if event.service.as_ref() != Some(&self.cfg.container.name) {
+            return;
+        }
+
+        let is_replica = replicas::plan(&self.cfg.container)
+            .map(|replicas| replicas.iter().any(|r| r.name == event.name))
+            .unwrap_or(false);
+        match event.action {
+            ContainerAction::HealthStatus(status) => {
+                if status == "healthy" {
+                    log::info!("container {} is healthy", event.name);
+                } else {
+                    log::warn!("container {} is {}", event.name, status);
+                }
+                // A canary's health is checked by the soak instead
+                if is_replica {
+                    self.update_state(|state| state.health = Some(status));
+                }
+            }
+            ContainerAction::Oom => {
+                log::warn!("container {} ran out of memory", event.name)
+            }
+            // Events for containers a deploy has replaced arrive after the deploy, by which point
+            // the poll finds the new container running and does nothing
+            ContainerAction::Die | ContainerAction::Destroy => {
+                log::info!(
+                    "container {} {:?}, checking on it",
+                    event.name,
+                    event.action
+                );
+                self.poll().await;
+            }
+        }
+    }
+
+    /// Find out how a dead container exited, and what it last logged
+    async fn crash_report(&self, container_id: &str) -> Option<CrashReport> {
+        let exit = match self.docker.inspect_container(container_id).await {
+            Ok(exit) => exit,
+            Err(e) => {
+                log::warn!("error inspecting dead container {}: {:?}", container_id, e);
+                return None;
+            }
+        };
+
+        let options = LogsRequest {
+            tail: Some(self.cfg.container.crash_log_lines),
+            ..Default::default()
+        };
+        let mut output = String::new();
+        let mut stream = self.docker.container_logs(container_id, options);
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(chunk) => output.push_str(&chunk),
+                Err(e) => {
+                    log::warn!(
+                        "error fetching logs of dead container {}: {:?}",
+                        container_id,
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+
+        let crash = CrashReport {
+            exit_code: exit.exit_code,
+            oom_killed: exit.oom_killed,
+            finished_at: exit.finished_at,
+            error: exit.error,
+            logs: output.lines().map(str::to_string).collect(),
+        };
+        log::warn!("container {} {}", self.cfg.container.name, crash.describe());
+        Some(crash)
+    }
+
+    /// Deploy, going back to the last good image if a post-deploy hook fails and the config asks
+    /// for that
+    async fn deploy(&mut self, trigger: TriggerSource) {
+        let e = match self.run_deployment(trigger, None).await {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+
+        let post_deploy_failed = e
+            .downcast_ref::<HookError>()
+            .is_some_and(|hook| hook.stage == HookStage::PostDeploy);
+        if !(post_deploy_failed && self.cfg.hooks.rollback_on_failure) {
+            return;
+        }
+
+        let target = self
+            .state
+            .read()
+            .unwrap()
+            .last_succeeded_image()
+            .map(str::to_string);
+        match target {
+            Some(target) => {
+                log::warn!("post-deploy hook failed, rolling back to {}", target);
+                // Failures are recorded and notified like any other deploy
+                let _ = self
+                    .run_deployment(TriggerSource::Rollback, Some(target))
+                    .await;
+            }
+            None => log::warn!("post-deploy hook failed, but there is no image to roll back to"),
+        }
+    }
+
+    /// Run a deployment, keeping its record in the shared state up to date. Rollbacks go to
+    /// `rollback_target` if given, otherwise the previously deployed image.
+    async fn run_deployment(
+        &mut self,
+        trigger: TriggerSource,
+        rollback_target: Option<String>,
+    ) -> Result<()> {
+        let deployment = Deployment::new(trigger, self.cfg.image.reference());
+        let log = DeployLog::default();
+        // The loop handles no heartbeats until the deployment is done
+        let _busy = Busy::start(&self.state);
+        deploylog::scope(
+            deployment.id,
+            log.clone(),
+            self.track_deployment(deployment, log, rollback_target),
+        )
+        .await
+    }
+
+    /// The body of `run_deployment`, run with its log records captured in `log`
+    async fn track_deployment(
+        &mut self,
+        mut deployment: Deployment,
+        log: DeployLog,
+        rollback_target: Option<String>,
+    ) -> Result<()> {
+        let trigger = deployment.trigger;
+        if trigger == TriggerSource::Poll {
+            deployment.crash = self.pending_crash.take();
+            self.restart_pending = false;
+        }
+        log::info!("starting deployment {} ({:?})", deployment.id, trigger);
+        metrics::TRIGGERS
+            .with_label_values(&[&trigger.to_string()])
+            .inc();
+        let timer = metrics::DEPLOY_DURATION.start_timer();
+        self.update_state(|state| {
+            state.record(deployment.clone());
+            state.attach_log(deployment.id, log);
+        });
+        self.notify(NotificationKind::DeployStarted, &deployment, None);
+
+        let res = match trigger {
+            TriggerSource::Rollback => self.rollback(&mut deployment, rollback_target).await,
+            _ => self.trigger_refresh(&mut deployment).await,
+        };
+
+        timer.observe_duration();
+        deployment.finished_at = Some(Utc::now());
+        match &res {
+            Ok(_) => {
+                metrics::DEPLOYS.with_label_values(&["success"]).inc();
+                deployment.outcome = Outcome::Succeeded;
+                let kind = match trigger {
+                    TriggerSource::Rollback => NotificationKind::RolledBack,
+                    _ => NotificationKind::DeploySucceeded,
+                };
+                self.notify(kind, &deployment, None);
+            }
+            Err(e) => {
+                metrics::DEPLOYS.with_label_values(&["failure"]).inc();
+                log::warn!("error in handler: {:?}", e);
+                deployment.outcome = Outcome::Failed;
+                deployment.error = Some(format!("{:#}", e));
+                self.notify(NotificationKind::DeployFailed, &deployment, Some(e));
+            }
+        }
+        self.activity.publish(Activity::DeployFinished {
+            outcome: deployment.outcome,
+            error: deployment.error.clone(),
+        });
+        self.update_state(|state| state.record(deployment));
+        res
+    }
+
+    fn notify(
+        &self,
+        kind: NotificationKind,
+        deployment: &Deployment,
+        error: Option<&anyhow::Error>,
+    ) {
+        let mut notification = Notification::new(kind, &self.cfg.container.name, &deployment.image);
+        notification.deploy_id = Some(deployment.id);
+        notification.trigger = Some(deployment.trigger);
+        notification.image_digest = deployment.image_digest.clone();
+        notification.duration_secs = deployment
+            .finished_at
+            .map(|t| (t - deployment.started_at).num_milliseconds() as f64 / 1000.0);
+        notification.error = deployment.error.clone();
+        notification.crash = deployment.crash.clone();
+        if let Some(e) = error {
+            notification.error_chain = e.chain().map(|cause| cause.to_string()).collect();
+        }
+        self.notifiers.send(notification);
+    }
+
+    fn notify_restart(&self) {
+        let mut notification = Notification::new(
+            NotificationKind::ContainerRestarted,
+            &self.cfg.container.name,
+            self.cfg.image.reference(),
+        );
+        notification.crash = self.pending_crash.clone();
+        self.notifiers.send(notification);
+    }
+
+    async fn trigger_refresh(&mut self, deployment: &mut Deployment) -> Result<()> {
+        match self.cfg.build.clone() {
+            Some(build) => {
+                let commit = build::build(
+                    &self.docker,
+                    &build,
+                    &self.cfg.image,
+                    &self.cfg.container.name,
+                )
+                .await
+                .context("building image")?;
+                deployment.commit = Some(commit);
+            }
+            None => self.pull_image().await?,
+        }
+        sidecars::pull(&self.docker, &self.cfg.sidecars).await?;
+        self.resolve_image(deployment).await?;
+        self.ensure_networks().await?;
+        // Sidecars come up first, as pre-deploy hooks may well need them
+        self.ensure_sidecars(deployment.trigger == TriggerSource::Poll)
+            .await?;
+        // Pre-deploy hooks run before the old container is touched, so a failure leaves it running
+        hooks::run_all(
+            &self.docker,
+            HookStage::PreDeploy,
+            &self.cfg.hooks.pre_deploy,
+            &self.cfg.container,
+            deployment,
+        )
+        .await?;
+        let use_canary = matches!(
+            deployment.trigger,
+            TriggerSource::Api | TriggerSource::Webhook
+        );
+        if use_canary && self.cfg.canary.is_some() {
+            self.run_canary(deployment).await?;
+        }
+        // Rolling out removes the canary along with the old containers
+        self.roll_out(deployment).await?;
+        hooks::run_all(
+            &self.docker,
+            HookStage::PostDeploy,
+            &self.cfg.hooks.post_deploy,
+            &self.cfg.container,
+            deployment,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Run the new image next to the old container for the soak period, tearing the canary down
+    /// if it fails
+    async fn run_canary(&mut self, deployment: &Deployment) -> Result<()> {
+        let canary = match self.cfg.canary.clone() {
+            Some(canary) => canary,
+            None => return Ok(()),
+        };
+        let name = canary::container_name(&self.cfg.container.name, &canary);
+
+        log::info!("starting canary {}", name);
+        // Clear out a canary left behind by an earlier deploy that was interrupted
+        self.docker.remove_container(&name).await?;
+        // The canary must not take the service's addresses or aliases from the running container
+        let networks = self
+            .cfg
+            .container
+            .networks
+            .iter()
+            .map(NetworkConfig::anonymous)
+            .collect();
+        self.start_container(deployment, &name, canary.ports.clone(), networks)
+            .await
+            .context("starting canary")?;
+
+        if let Err(e) = canary::soak(&self.docker, &name, &canary).await {
+            log::warn!("canary {} failed, removing it", name);
+            let stop_timeout = self.cfg.container.stop.timeout;
+            if let Err(e) = self.docker.stop_container(&name, stop_timeout).await {
+                log::warn!("error stopping canary {}: {:#}", name, e);
+            }
+            if let Err(e) = self.docker.remove_container(&name).await {
+                log::warn!("error removing canary {}: {:#}", name, e);
+            }
+            return Err(e.context("canary failed"));
+        }
+
+        log::info!("canary {} passed, promoting", name);
         Ok(())
     }
 
+    /// Replace the running container with one running `target`, or the previously deployed image
+    async fn rollback(
+        &mut self,
+        deployment: &mut Deployment,
+        target: Option<String>,
+    ) -> Result<()> {
+        let target = match target {
+            Some(target) => target,
+            None => self
+                .state
+                .read()
+                .unwrap()
+                .rollback_target()
+                .map(str::to_string)
+                .context("no previous deployment to roll back to")?,
+        };
+        log::info!("rolling back to image {}", target);
+        self.activity.publish(Activity::RollbackStarted {
+            image: target.clone(),
+        });
+
+        deployment.image = target;
+        self.resolve_image(deployment).await?;
+        self.ensure_networks().await?;
+        self.ensure_sidecars(true).await?;
+        self.roll_out(deployment).await?;
+        Ok(())
+    }
+
+    /// Record exactly which image a deployment is running, as tags move between deployments
+    async fn resolve_image(&self, deployment: &mut Deployment) -> Result<()> {
+        let details = self
+            .docker
+            .inspect_image(&deployment.image)
+            .await
+            .context("inspecting image")?;
+        deployment.image_id = Some(details.id);
+        deployment.image_digest = details.digest;
+        Ok(())
+    }
+
+    fn update_state<F: FnOnce(&mut State)>(&self, f: F) {
+        let mut state = self.state.write().unwrap();
+        f(&mut state);
+    }
+
     async fn pull_image(&mut self) -> Result<()> {
         use dockerclient::CreateImageOptions;
 
@@ -140,32 +695,219 @@ impl<D: DockerApi> Controller<D> {
             tag: self.cfg.image.tag.as_str(),
         };
 
-        self.docker.create_image(options).await
+        let image = self.cfg.image.reference();
+        self.activity.publish(Activity::PullStarted {
+            image: image.clone(),
+        });
+        let _timer = metrics::PULL_DURATION.start_timer();
+        self.docker.create_image(options).await?;
+        self.activity.publish(Activity::PullFinished { image });
+        Ok(())
     }
 
-    async fn stop_running_contianer(&mut self) -> Result<()> {
-        log::info!("stopping running container");
+    /// Replace the service's replicas with containers of the deployment's image, a batch at a
+    /// time, waiting for each batch to become ready before moving on. Restarts only replace the
+    /// replicas that are not running. Any other containers of the service, such as a promoted
+    /// canary or replicas left over from scaling down, are removed first.
+    async fn roll_out(&mut self, deployment: &Deployment) -> Result<()> {
+        let replicas = replicas::plan(&self.cfg.container)?;
+        let existing = self.service_containers(&replicas).await?;
+        let restart = deployment.trigger == TriggerSource::Poll;
+        let batch_size = usize::from(self.cfg.rollout.batch_size);
 
-        match self.docker.remove_container(&self.cfg.container.name).await {
-            Ok(_) => {}
-            Err(e) => match e.downcast_ref::<bollard::errors::Error>() {
-                Some(e) => match e.kind() {
-                    bollard::errors::ErrorKind::DockerResponseNotFoundError { .. } => {
-                        log::debug!("running container not found")
-                    }
-                    _ => anyhow::bail!("bad"),
-                },
-                None => anyhow::bail!("bad"),
-            },
+        // These may hold ports the new replicas need, e.g. when going from one replica to several
+        for container in existing
+            .iter()
+            .filter(|c| !replicas.iter().any(|r| r.name == c.name))
+        {
+            self.remove_gracefully(container).await?;
+        }
+
+        for batch in replicas.chunks(batch_size) {
+            let mut started = Vec::new();
+            for replica in batch {
+                let old = existing.iter().find(|c| c.name == replica.name);
+                if restart && old.is_some_and(|c| c.running) {
+                    log::debug!("replica {} is running, leaving it", replica.name);
+                    continue;
+                }
+                if let Some(old) = old {
+                    self.remove_gracefully(old).await?;
+                }
+                self.run_container(deployment, replica).await?;
+                started.push(replica);
+            }
+            for replica in started {
+                self.wait_until_ready(replica).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every container belonging to this service, including one from before the daemon labelled
+    /// its containers, refusing to go on if another service's container has a replica's name
+    async fn service_containers(&self, replicas: &[Replica]) -> Result<Vec<ContainerSummary>> {
+        let name = &self.cfg.container.name;
+        let service_filter = format!("{}={}", labels::SERVICE, name);
+        let mut containers = self
+            .docker
+            .list_containers(ContainerFilter::Label(&service_filter))
+            .await
+            .context("listing service containers")?;
+
+        // Unlabelled containers are only found by name
+        let named = self
+            .docker
+            .list_containers(ContainerFilter::Replicas(name))
+            .await
+            .context("looking for existing container")?;
+        for container in named {
+            if container.labels.get(labels::SERVICE) == Some(name) {
+                continue;
+            }
+            if labels::is_legacy(&container, name) {
+                log::info!(
+                    "replacing container {} ({}), which was created before dockerdeploy labelled its containers",
+                    container.name,
+                    container.id
+                );
+                containers.push(container);
+            } else if replicas.iter().any(|r| r.name == container.name) {
+                anyhow::bail!(
+                    "container {} ({}) was not created by dockerdeploy for this service, remove it to deploy",
+                    container.name,
+                    container.id
+                );
+            }
+        }
+
+        Ok(containers)
+    }
+
+    async fn remove_gracefully(&self, container: &ContainerSummary) -> Result<()> {
+        if container.running {
+            self.stop_gracefully(container).await;
+        }
+        log::debug!("removing container {} ({})", container.name, container.id);
+        self.docker
+            .remove_container(&container.id)
+            .await
+            .with_context(|| format!("removing container {}", container.name))
+    }
+
+    /// Run the pre-stop hook and send the stop signal. Failures are only logged, as the container
+    /// is forcibly removed afterwards regardless.
+    async fn stop_gracefully(&self, container: &ContainerSummary) {
+        let stop = &self.cfg.container.stop;
+
+        if let Some(hook) = &stop.pre_stop {
+            log::info!("running pre-stop hook for {}", container.name);
+            let timeout = std::time::Duration::from_secs(hook.timeout);
+            match tokio::time::timeout(timeout, self.pre_stop(container, hook)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log::warn!("pre-stop hook for {} failed: {:#}", container.name, e),
+                Err(_) => log::warn!(
+                    "pre-stop hook for {} timed out after {}s",
+                    container.name,
+                    hook.timeout
+                ),
+            }
+        }
+
+        log::info!("stopping container {}", container.name);
+        if let Err(e) = self
+            .docker
+            .stop_container(&container.id, stop.timeout)
+            .await
+        {
+            log::warn!(
+                "error stopping container {}, forcing removal: {:#}",
+                container.name,
+                e
+            );
         }
+        self.activity.publish(Activity::ContainerStopped {
+            container: container.name.clone(),
+        });
+    }
 
+    async fn pre_stop(&self, container: &ContainerSummary, hook: &PreStopConfig) -> Result<()> {
+        if let Some(url) = &hook.http {
+            reqwest::get(url)
+                .await
+                .with_context(|| format!("requesting {}", url))?
+                .error_for_status()?;
+        }
+        if let Some(cmd) = &hook.exec {
+            let code = self.docker.exec(&container.id, cmd).await?;
+            if code != 0 {
+                anyhow::bail!("{:?} exited with status {}", cmd, code);
+            }
+        }
         Ok(())
     }
 
-    async fn run_container(&mut self) -> Result<()> {
-        log::info!("running new container");
+    async fn wait_until_ready(&self, replica: &Replica) -> Result<()> {
+        match &self.cfg.readiness {
+            Some(config) => {
+                log::info!("waiting for container {} to become ready", replica.name);
+                let config = replicas::readiness(config, replica)?;
+                readiness::wait(&config)
+                    .await
+                    .with_context(|| format!("readiness probe for {} failed", replica.name))
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn ensure_sidecars(&self, restart_only: bool) -> Result<()> {
+        // Deploys still look for sidecars that have been taken out of the config
+        if restart_only && self.cfg.sidecars.is_empty() {
+            return Ok(());
+        }
+        sidecars::ensure(
+            &self.docker,
+            &self.cfg.container,
+            &self.cfg.sidecars,
+            restart_only,
+        )
+        .await
+        .context("starting sidecars")
+    }
+
+    async fn ensure_networks(&self) -> Result<()> {
+        for network in &self.cfg.container.networks {
+            self.docker
+                .ensure_network(network)
+                .await
+                .with_context(|| format!("preparing network {}", network.name))?;
+        }
+        Ok(())
+    }
 
-        let image = format!("{}:{}", self.cfg.image.name, self.cfg.image.tag);
+    async fn run_container(&mut self, deployment: &Deployment, replica: &Replica) -> Result<()> {
+        log::info!("running new container {}", replica.name);
+
+        let networks = self.cfg.container.networks.clone();
+        self.start_container(deployment, &replica.name, replica.ports.clone(), networks)
+            .await?;
+
+        // Health events are only sent on changes, so forget the old container's status
+        self.update_state(|state| state.health = None);
+
+        Ok(())
+    }
+
+    /// Start a container of the service under `name`, with `ports` and `networks` in place of the
+    /// configured ones
+    async fn start_container(
+        &self,
+        deployment: &Deployment,
+        name: &str,
+        ports: Vec<PortConfig>,
+        networks: Vec<NetworkConfig>,
+    ) -> Result<()> {
         let cmd = self
             .cfg
             .container
@@ -174,23 +916,38 @@ impl<D: DockerApi> Controller<D> {
             .map(|s| s.as_ref())
             .collect();
 
-        let ports = self.cfg.container.ports.clone();
         let mounts = self.cfg.container.mounts.clone();
 
         let res = self
             .docker
             .run_container(crate::dockerclient::RunContainerOptions {
-                name: &self.cfg.container.name,
-                image: &image,
+                name,
+                image: &deployment.image,
                 cmd,
                 ports,
                 mounts,
+                networks,
+                network_mode: self.cfg.container.network_mode.clone(),
+                runtime: self.cfg.container.runtime.clone(),
+                labels: labels::for_deployment(
+                    &self.cfg.container.name,
+                    &self.cfg.container.hash,
+                    deployment,
+                    &self.cfg.container.labels,
+                ),
+                env: self.cfg.container.env.clone(),
+                healthcheck: self.cfg.container.healthcheck.clone(),
+                stop: self.cfg.container.stop.clone(),
             })
             .await?;
 
         for warning in res.warnings {
             log::warn!("run_container warning: {}", warning);
         }
+        self.activity.publish(Activity::ContainerStarted {
+            container: name.to_string(),
+            image: deployment.image.clone(),
+        });
 
         Ok(())
     }
@@ -202,27 +959,55 @@ impl<D: DockerApi> Controller<D> {
     pub(crate) fn config(&self) -> &config::DockerDeployConfig {
         &self.cfg
     }
+
+    pub(crate) fn state(&self) -> SharedState {
+        self.state.clone()
+    }
+
+    pub(crate) fn activity(&self) -> Publisher {
+        self.activity.clone()
+    }
 }
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "dockerdeploy", author = "Simon Walker")]
 struct Opts {
     #[structopt(short, long, help = "Config file to parse", parse(from_os_str))]
-    config: PathBuf,
+    config: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<client::Command>,
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
+    deploylog::init();
 
     let opts = Opts::from_args();
     log::trace!("command line options: {:?}", opts);
 
+    match opts.command {
+        Some(command) => {
+            if let Err(e) = client::run(command, opts.config.as_deref()).await {
+                eprintln!("error: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+        None => {
+            let config = opts
+                .config
+                .expect("a config file is required to run the daemon");
+            serve(config).await;
+        }
+    }
+}
+
+async fn serve(config_file: PathBuf) {
     let (tx, rx) = unbounded_channel();
 
     let docker = Docker::connect_with_local_defaults().expect("connecting to docker");
-    let mut controller =
-        Controller::new(docker, opts.config.clone(), tx.clone(), rx).expect("creating controller");
+    let mut controller = Controller::new(docker.clone(), config_file.clone(), tx.clone(), rx)
+        .expect("creating controller");
 
     let watcher_tx = tx.clone();
     let mut watcher: RecommendedWatcher =
@@ -237,10 +1022,11 @@ async fn main() {
         .expect("creating watcher");
 
     watcher
-        .watch(&opts.config, RecursiveMode::NonRecursive)
+        .watch(&config_file, RecursiveMode::NonRecursive)
         .expect("failed to start watcher");
+    controller.set_watcher(watcher);
 
-    // Start the poll loop
+    // Start the poll loop, a fallback for container events missed while the subscription is down
     let poll_tx = tx.clone();
     tokio::spawn(async move {
         log::info!("starting poll loop");
@@ -253,45 +1039,67 @@ async fn main() {
     });
 
     let key = controller.validation_key();
-    let ip_address: IpAddr = controller
+    let state = controller.state();
+    let activity = controller.activity();
+    let heartbeat_config = controller.config().heartbeat.clone();
+    let address = controller
         .config()
-        .server
-        .as_ref()
-        .and_then(|s| s.ip_address.clone())
-        .unwrap_or_else(|| "127.0.0.1".to_string())
-        .parse()
-        .expect("parsing IP address");
-    let port = controller
-        .config()
-        .server
-        .as_ref()
-        .and_then(|s| s.port)
-        .unwrap_or(8080);
+        .listen_address()
+        .expect("reading server address");
 
     tokio::spawn(async move {
         controller.event_loop().await;
     });
 
-    let api = routes::build(tx, key);
+    tokio::spawn(events::run(tx.clone(), docker.clone()));
+
+    let api = routes::build(
+        tx.clone(),
+        state.clone(),
+        activity,
+        docker,
+        key,
+        &heartbeat_config,
+    );
+
+    tokio::spawn(heartbeat::run(tx, state, heartbeat_config));
     let routes = api.with(warp::log("dockerdeploy"));
 
-    warp::serve(routes).run((ip_address, port)).await;
+    warp::serve(routes).run(address).await;
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::dockerclient::{
-        CreateContainerResults, CreateImageOptions, DockerApi, RunContainerOptions,
+        BuildOptions, ContainerDetails, ContainerFilter, ContainerSummary, CreateContainerResults,
+        CreateImageOptions, DockerApi, EventStream, ImageDetails, LogStream, LogsRequest,
+        RunContainerOptions,
     };
     use anyhow::Result;
     use async_trait::async_trait;
 
+    #[derive(Clone)]
     struct MockDocker;
 
     #[async_trait]
     impl DockerApi for MockDocker {
-        async fn is_container_running(&self, _container_name: &str) -> Result<bool> {
+        async fn list_containers(
+            &self,
+            _filter: ContainerFilter<'_>,
+        ) -> Result<Vec<ContainerSummary>> {
+            todo!()
+        }
+
+        async fn stop_container(&self, _container_name: &str, _timeout: u64) -> Result<()> {
+            todo!()
+        }
+
+        async fn wait_container(&self, _container_name: &str) -> Result<i64> {
+            todo!()
+        }
+
+        async fn inspect_container(&self, _container_name: &str) -> Result<ContainerDetails> {
             todo!()
         }
 
@@ -299,6 +1107,10 @@ mod tests {
             todo!()
         }
 
+        async fn exec(&self, _container_name: &str, _cmd: &[String]) -> Result<i64> {
+            todo!()
+        }
+
         async fn run_container<'a>(
             &'a self,
             _options: RunContainerOptions<'a>,
@@ -309,6 +1121,26 @@ mod tests {
         async fn create_image<'a>(&'a self, _options: CreateImageOptions<'a>) -> Result<()> {
             todo!()
         }
+
+        async fn ensure_network(&self, _network: &NetworkConfig) -> Result<()> {
+            todo!()
+        }
+
+        async fn inspect_image(&self, _image: &str) -> Result<ImageDetails> {
+            todo!()
+        }
+
+        fn container_logs(&self, _container_name: &str, _options: LogsRequest) -> LogStream {
+            todo!()
+        }
+
+        fn build_image(&self, _options: BuildOptions, _context: Vec<u8>) -> LogStream {
+            todo!()
+        }
+
+        fn container_events(&self, _label: &str) -> EventStream {
+            todo!()
+        }
     }
 
     #[tokio::test]
@@ -318,4 +1150,61 @@ mod tests {
         let config = PathBuf::from("config.toml.example");
         let _controller = Controller::new(docker, config, tx, rx).unwrap();
     }
+
+    #[tokio::test]
+    async fn test_routes_require_token() {
+        let mut state = State::new("foobar", "python:3.8");
+        state.auth = Some(
+            toml::from_str(
+                "[[tokens]]\nname = \"viewer\"\ntoken = \"viewer-0123456789abcdef\"\nscopes = [\"read\"]",
+            )
+            .unwrap(),
+        );
+        let (tx, _rx) = unbounded_channel();
+        let heartbeat_config =
+            toml::from_str("sleep_time = 10\nendpoint = \"/heartbeat\"").unwrap();
+        let api = routes::build(
+            tx,
+            state.shared(),
+            Publisher::new(),
+            MockDocker,
+            None,
+            &heartbeat_config,
+        );
+        let request = |method: &str, path: &str, token: Option<&str>| {
+            let mut request = warp::test::request().method(method).path(path);
+            if let Some(token) = token {
+                request = request.header("authorization", format!("Bearer {}", token));
+            }
+            request
+        };
+        let viewer = Some("viewer-0123456789abcdef");
+
+        let res = request("GET", "/status", None).reply(&api).await;
+        assert_eq!(res.status(), 401);
+        assert_eq!(res.headers()["www-authenticate"], "Bearer");
+        assert_eq!(
+            request("GET", "/status", viewer).reply(&api).await.status(),
+            200
+        );
+        assert_eq!(
+            request("POST", "/trigger", viewer)
+                .reply(&api)
+                .await
+                .status(),
+            403
+        );
+        assert_eq!(
+            request("GET", "/audit", viewer).reply(&api).await.status(),
+            403
+        );
+        assert_eq!(request("GET", "/", None).reply(&api).await.status(), 200);
+        assert_eq!(
+            request("GET", "/missing", viewer)
+                .reply(&api)
+                .await
+                .status(),
+            404
+        );
+    }
 }
@@ -0,0 +1,92 @@
+//! Prometheus metrics, served from `/metrics`
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, Histogram, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+/// Pulls and deploys take seconds to minutes, rather than the milliseconds the default buckets
+/// are aimed at
+const DURATION_BUCKETS: &[f64] = &[0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+/// Content type of the Prometheus text exposition format
+pub(crate) const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+lazy_static! {
+    pub(crate) static ref WEBHOOKS_RECEIVED: IntCounter = register_int_counter!(
+        "dockerdeploy_webhooks_received_total",
+        "Webhook requests received"
+    )
+    .unwrap();
+    pub(crate) static ref WEBHOOKS_ACCEPTED: IntCounter = register_int_counter!(
+        "dockerdeploy_webhooks_accepted_total",
+        "Webhook requests that triggered a deploy"
+    )
+    .unwrap();
+    pub(crate) static ref WEBHOOKS_REJECTED: IntCounterVec = register_int_counter_vec!(
+        "dockerdeploy_webhooks_rejected_total",
+        "Webhook requests that did not trigger a deploy",
+        &["reason"]
+    )
+    .unwrap();
+    pub(crate) static ref TRIGGERS: IntCounterVec = register_int_counter_vec!(
+        "dockerdeploy_triggers_total",
+        "Deploys requested, by what requested them",
+        &["source"]
+    )
+    .unwrap();
+    pub(crate) static ref DEPLOYS: IntCounterVec = register_int_counter_vec!(
+        "dockerdeploy_deploys_total",
+        "Finished deploys, by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    pub(crate) static ref POLL_RESTARTS: IntCounter = register_int_counter!(
+        "dockerdeploy_poll_restarts_total",
+        "Times the poll loop found the container missing and restarted it"
+    )
+    .unwrap();
+    pub(crate) static ref PULL_DURATION: Histogram = register_histogram!(
+        "dockerdeploy_pull_duration_seconds",
+        "Time taken to pull the image",
+        DURATION_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub(crate) static ref DEPLOY_DURATION: Histogram = register_histogram!(
+        "dockerdeploy_deploy_duration_seconds",
+        "Time taken for a whole deploy, including the pull",
+        DURATION_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub(crate) static ref CONTAINER_UP: IntGauge = register_int_gauge!(
+        "dockerdeploy_container_up",
+        "Whether the managed container was running at the last check"
+    )
+    .unwrap();
+}
+
+/// Render every registered metric in the Prometheus text format
+pub(crate) fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("encoding metrics");
+    String::from_utf8(buffer).expect("metrics are valid utf-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        WEBHOOKS_REJECTED.with_label_values(&["unauthorized"]).inc();
+        CONTAINER_UP.set(1);
+
+        let text = render();
+
+        assert!(text.contains("dockerdeploy_webhooks_rejected_total{reason=\"unauthorized\"}"));
+        assert!(text.contains("dockerdeploy_container_up 1"));
+    }
+}
@@ -0,0 +1,555 @@
+//! Notifications about deploys, sent to external services
+//!
+//! Each configured target becomes a [`Notifier`]. The controller hands every [`Notification`] to
+//! [`Notifiers::send`], which delivers them in the background so a slow or broken target never
+//! holds up a deploy.
+
+use crate::config::{EmailConfig, NotificationsConfig, WebhookConfig, WebhookFormat};
+use crate::state::{CrashReport, TriggerSource};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+use std::fmt::Write;
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum NotificationKind {
+    DeployStarted,
+    DeploySucceeded,
+    DeployFailed,
+    RolledBack,
+    ContainerRestarted,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct Notification {
+    pub(crate) kind: NotificationKind,
+    pub(crate) container: String,
+    pub(crate) image: String,
+    pub(crate) image_digest: Option<String>,
+    pub(crate) deploy_id: Option<Uuid>,
+    pub(crate) trigger: Option<TriggerSource>,
+    pub(crate) duration_secs: Option<f64>,
+    pub(crate) error: Option<String>,
+    /// The error and each of its causes, outermost first
+    pub(crate) error_chain: Vec<String>,
+    pub(crate) crash: Option<CrashReport>,
+}
+
+impl Notification {
+    pub(crate) fn new(
+        kind: NotificationKind,
+        container: impl Into<String>,
+        image: impl Into<String>,
+    ) -> Self {
+        Notification {
+            kind,
+            container: container.into(),
+            image: image.into(),
+            image_digest: None,
+            deploy_id: None,
+            trigger: None,
+            duration_secs: None,
+            error: None,
+            error_chain: Vec::new(),
+            crash: None,
+        }
+    }
+
+    pub(crate) fn title(&self) -> &'static str {
+        match self.kind {
+            NotificationKind::DeployStarted => "Deploy started",
+            NotificationKind::DeploySucceeded => "Deploy succeeded",
+            NotificationKind::DeployFailed => "Deploy failed",
+            NotificationKind::RolledBack => "Rolled back",
+            NotificationKind::ContainerRestarted => "Container restarted",
+        }
+    }
+
+    /// One line description, suitable for chat messages
+    pub(crate) fn summary(&self) -> String {
+        let mut summary = match self.kind {
+            NotificationKind::DeployStarted => {
+                format!("Deploying {} to `{}`", self.image, self.container)
+            }
+            NotificationKind::DeploySucceeded => {
+                format!("Deployed {} to `{}`", self.image, self.container)
+            }
+            NotificationKind::DeployFailed => {
+                format!("Failed to deploy {} to `{}`", self.image, self.container)
+            }
+            NotificationKind::RolledBack => {
+                format!("Rolled `{}` back to {}", self.container, self.image)
+            }
+            NotificationKind::ContainerRestarted => match &self.crash {
+                Some(crash) => format!(
+                    "Container `{}` {}, restarting it",
+                    self.container,
+                    crash.describe()
+                ),
+                None => format!(
+                    "Container `{}` was not running, restarting it",
+                    self.container
+                ),
+            },
+        };
+
+        if let Some(trigger) = self.trigger {
+            summary.push_str(&format!(" (triggered by {})", trigger));
+        }
+        if let Some(error) = &self.error {
+            summary.push_str(&format!(": {}", error));
+        }
+        summary
+    }
+
+    fn colour(&self) -> &'static str {
+        match self.kind {
+            NotificationKind::DeploySucceeded => "2EB886",
+            NotificationKind::DeployFailed | NotificationKind::ContainerRestarted => "A30200",
+            NotificationKind::DeployStarted | NotificationKind::RolledBack => "DAA038",
+        }
+    }
+}
+
+#[async_trait]
+pub(crate) trait Notifier {
+    async fn notify(&self, notification: &Notification) -> Result<()>;
+}
+
+/// All of the configured notification targets
+#[derive(Default, Clone)]
+pub(crate) struct Notifiers {
+    notifiers: Vec<Arc<dyn Notifier + Send + Sync>>,
+}
+
+impl Notifiers {
+    pub(crate) fn from_config(config: Option<&NotificationsConfig>) -> Self {
+        let mut notifiers: Vec<Arc<dyn Notifier + Send + Sync>> = Vec::new();
+
+        if let Some(config) = config {
+            for webhook in &config.webhooks {
+                notifiers.push(Arc::new(WebhookNotifier::new(webhook.clone())));
+            }
+            for email in &config.email {
+                notifiers.push(Arc::new(EmailNotifier::new(email.clone())));
+            }
+        }
+
+        Notifiers { notifiers }
+    }
+
+    /// Deliver a notification to every target in the background
+    pub(crate) fn send(&self, notification: Notification) {
+        for notifier in &self.notifiers {
+            let notifier = notifier.clone();
+            let notification = notification.clone();
+            tokio::spawn(async move {
+                if let Err(e) = notifier.notify(&notification).await {
+                    log::warn!("error sending notification: {:?}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Posts a JSON body to an HTTP endpoint
+pub(crate) struct WebhookNotifier {
+    config: WebhookConfig,
+    client: reqwest::Client,
+}
+
+impl WebhookNotifier {
+    pub(crate) fn new(config: WebhookConfig) -> Self {
+        WebhookNotifier {
+            config,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn body(&self, notification: &Notification) -> Result<String> {
+        let template = match (&self.config.template, self.config.format) {
+            (Some(template), _) => template.as_str(),
+            (None, WebhookFormat::Generic) => return Ok(serde_json::to_string(notification)?),
+            (None, WebhookFormat::Slack) | (None, WebhookFormat::Mattermost) => SLACK_TEMPLATE,
+            (None, WebhookFormat::Teams) => TEAMS_TEMPLATE,
+        };
+
+        Ok(render(template, notification))
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<()> {
+        log::debug!(
+            "sending {:?} notification to {}",
+            notification.kind,
+            self.config.url
+        );
+
+        self.client
+            .post(&self.config.url)
+            .header("Content-Type", "application/json")
+            .body(self.body(notification)?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Sends a plain text summary over SMTP
+pub(crate) struct EmailNotifier {
+    config: EmailConfig,
+}
+
+impl EmailNotifier {
+    pub(crate) fn new(config: EmailConfig) -> Self {
+        EmailNotifier { config }
+    }
+
+    fn subject(notification: &Notification) -> String {
+        format!(
+            "[dockerdeploy] {}: {}",
+            notification.title(),
+            notification.container
+        )
+    }
+
+    fn body(notification: &Notification) -> String {
+        let optional = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
+
+        let mut body = String::new();
+        writeln!(body, "{}", notification.summary()).unwrap();
+        writeln!(body).unwrap();
+        writeln!(body, "Container:    {}", notification.container).unwrap();
+        writeln!(body, "Image:        {}", notification.image).unwrap();
+        writeln!(
+            body,
+            "Image digest: {}",
+            optional(&notification.image_digest)
+        )
+        .unwrap();
+        writeln!(
+            body,
+            "Trigger:      {}",
+            optional(&notification.trigger.map(|t| t.to_string()))
+        )
+        .unwrap();
+        writeln!(
+            body,
+            "Deployment:   {}",
+            optional(&notification.deploy_id.map(|id| id.to_string()))
+        )
+        .unwrap();
+        writeln!(
+            body,
+            "Duration:     {}",
+            optional(&notification.duration_secs.map(|d| format!("{:.1}s", d)))
+        )
+        .unwrap();
+
+        if let Some(crash) = &notification.crash {
+            writeln!(body).unwrap();
+            writeln!(body, "Previous container {}", crash.describe()).unwrap();
+            if let Some(finished_at) = crash.finished_at {
+                writeln!(body, "Finished at: {}", finished_at.to_rfc3339()).unwrap();
+            }
+            if !crash.logs.is_empty() {
+                writeln!(body).unwrap();
+                writeln!(body, "Last {} log lines:", crash.logs.len()).unwrap();
+                for line in &crash.logs {
+                    writeln!(body, "{}", line).unwrap();
+                }
+            }
+        }
+
+        if let Some((error, causes)) = notification.error_chain.split_first() {
+            writeln!(body).unwrap();
+            writeln!(body, "Error: {}", error).unwrap();
+            for cause in causes {
+                writeln!(body, "Caused by: {}", cause).unwrap();
+            }
+        }
+
+        body
+    }
+
+    /// Build and send the email; this blocks on network IO
+    fn send(config: &EmailConfig, subject: String, body: String) -> Result<()> {
+        use lettre::smtp::authentication::Credentials;
+        use lettre::{ClientSecurity, ClientTlsParameters, SmtpClient, Transport};
+        use lettre_email::EmailBuilder;
+
+        let mut builder = EmailBuilder::new()
+            .from(config.from.as_str())
+            .subject(subject)
+            .text(body);
+        for to in &config.to {
+            builder = builder.to(to.as_str());
+        }
+        let email = builder.build().context("building email")?;
+
+        let security = if config.starttls {
+            let connector = native_tls::TlsConnector::new().context("creating TLS connector")?;
+            ClientSecurity::Required(ClientTlsParameters::new(config.host.clone(), connector))
+        } else {
+            ClientSecurity::None
+        };
+
+        let mut client = SmtpClient::new((config.host.as_str(), config.port), security)
+            .with_context(|| format!("connecting to {}:{}", config.host, config.port))?;
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            client = client.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        client
+            .transport()
+            .send(email.into())
+            .context("sending email")?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Notifier for EmailNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<()> {
+        log::debug!(
+            "emailing {:?} notification to {:?}",
+            notification.kind,
+            self.config.to
+        );
+
+        let config = self.config.clone();
+        let subject = Self::subject(notification);
+        // SMTP requires CRLF line endings, which lettre leaves to us
+        let body = Self::body(notification).replace('\n', "\r\n");
+        tokio::task::spawn_blocking(move || Self::send(&config, subject, body)).await?
+    }
+}
+
+/// Slack and Mattermost incoming webhooks both accept this
+const SLACK_TEMPLATE: &str = r#"{"text": "*{{title}}*: {{summary}}"}"#;
+
+const TEAMS_TEMPLATE: &str = r#"{
+    "@type": "MessageCard",
+    "@context": "https://schema.org/extensions",
+    "themeColor": "{{colour}}",
+    "summary": "{{title}}",
+    "title": "{{title}}",
+    "text": "{{summary}}"
+}"#;
+
+/// Fill in the `{{variable}}`s of a template
+///
+/// Values are JSON escaped but not quoted, so templates put them inside their own string
+/// literals.
+pub(crate) fn render(template: &str, notification: &Notification) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_default();
+
+    let variables = [
+        (
+            "event",
+            serde_json::to_value(notification.kind)
+                .ok()
+                .and_then(|v| v.as_str().map(str::to_string))
+                .unwrap_or_default(),
+        ),
+        ("title", notification.title().to_string()),
+        ("summary", notification.summary()),
+        ("colour", notification.colour().to_string()),
+        ("container", notification.container.clone()),
+        ("image", notification.image.clone()),
+        ("image_digest", optional(notification.image_digest.clone())),
+        (
+            "deploy_id",
+            optional(notification.deploy_id.map(|id| id.to_string())),
+        ),
+        (
+            "trigger",
+            optional(notification.trigger.map(|t| t.to_string())),
+        ),
+        (
+            "duration",
+            optional(notification.duration_secs.map(|d| format!("{:.1}", d))),
+        ),
+        ("error", optional(notification.error.clone())),
+        (
+            "exit_code",
+            optional(notification.crash.as_ref().map(|c| c.exit_code.to_string())),
+        ),
+        (
+            "oom_killed",
+            optional(
+                notification
+                    .crash
+                    .as_ref()
+                    .map(|c| c.oom_killed.to_string()),
+            ),
+        ),
+        (
+            "crash_logs",
+            optional(notification.crash.as_ref().map(|c| c.logs.join("\n"))),
+        ),
+    ];
+
+    variables
+        .iter()
+        .fold(template.to_string(), |body, (name, value)| {
+            body.replace(&format!("{{{{{}}}}}", name), &json_escape(value))
+        })
+}
+
+fn json_escape(value: &str) -> String {
+    let quoted = serde_json::to_string(value).expect("serialising a string");
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failed() -> Notification {
+        let mut notification =
+            Notification::new(NotificationKind::DeployFailed, "foobar", "python:3.8");
+        notification.trigger = Some(TriggerSource::Webhook);
+        notification.error = Some("pulling image: \"python:3.8\" not found".to_string());
+        notification
+    }
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(
+            failed().summary(),
+            "Failed to deploy python:3.8 to `foobar` (triggered by webhook): pulling image: \"python:3.8\" not found"
+        );
+    }
+
+    #[test]
+    fn test_crash_report() {
+        let mut notification =
+            Notification::new(NotificationKind::ContainerRestarted, "foobar", "python:3.8");
+        notification.crash = Some(CrashReport {
+            exit_code: 137,
+            oom_killed: true,
+            finished_at: None,
+            error: None,
+            logs: vec!["allocating".to_string(), "Killed".to_string()],
+        });
+
+        assert_eq!(
+            notification.summary(),
+            "Container `foobar` exited with status 137 (out of memory), restarting it"
+        );
+        let body = EmailNotifier::body(&notification);
+        assert!(body.contains("Last 2 log lines:\nallocating\nKilled\n"));
+        assert_eq!(
+            render("{{exit_code}} {{crash_logs}}", &notification),
+            "137 allocating\\nKilled"
+        );
+    }
+
+    #[test]
+    fn test_render_escapes_values() {
+        let body = render(SLACK_TEMPLATE, &failed());
+
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            value["text"],
+            format!("*Deploy failed*: {}", failed().summary())
+        );
+    }
+
+    #[test]
+    fn test_render_custom_template() {
+        let body = render(r#"{"event": "{{event}}", "who": "{{trigger}}"}"#, &failed());
+
+        assert_eq!(body, r#"{"event": "deploy_failed", "who": "webhook"}"#);
+    }
+
+    #[test]
+    fn test_teams_template_is_valid_json() {
+        let body = render(TEAMS_TEMPLATE, &failed());
+
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(value["themeColor"], "A30200");
+    }
+
+    /// A minimal SMTP server that accepts a single message and returns the whole conversation
+    fn smtp_sink() -> (u16, std::thread::JoinHandle<String>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer.write_all(b"220 sink ESMTP\r\n").unwrap();
+
+            let mut received = String::new();
+            let mut in_data = false;
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                received.push_str(&line);
+
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else {
+                    match line.get(..4).map(str::to_uppercase).as_deref() {
+                        Some("DATA") => {
+                            in_data = true;
+                            b"354 go ahead\r\n"
+                        }
+                        Some("QUIT") => {
+                            writer.write_all(b"221 bye\r\n").unwrap();
+                            break;
+                        }
+                        _ => b"250 ok\r\n",
+                    }
+                };
+                writer.write_all(reply).unwrap();
+            }
+            received
+        });
+
+        (port, handle)
+    }
+
+    #[tokio::test]
+    async fn test_email_notifier() {
+        let (port, sink) = smtp_sink();
+        let notifier = EmailNotifier::new(EmailConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            starttls: false,
+            username: None,
+            password: None,
+            from: "dockerdeploy@example.com".to_string(),
+            to: vec!["oncall@example.com".to_string()],
+        });
+
+        let mut notification = failed();
+        notification.image_digest = Some("python@sha256:abc".to_string());
+        notification.error_chain = vec!["pulling image".to_string(), "not found".to_string()];
+        notifier.notify(&notification).await.unwrap();
+
+        let received = sink.join().unwrap();
+        assert!(received.contains("RCPT TO:<oncall@example.com>"));
+        assert!(received.contains("Subject: [dockerdeploy] Deploy failed: foobar"));
+        assert!(received.contains("Image digest: python@sha256:abc"));
+        assert!(received.contains("Error: pulling image\r\nCaused by: not found"));
+    }
+}
@@ -0,0 +1,109 @@
+//! Readiness probe, run after a new container starts
+//!
+//! Docker considers the container started as soon as its process is running, which says nothing
+//! about whether the app came up. A deploy only succeeds once the probe passes.
+
+use crate::config::ReadinessConfig;
+use anyhow::{Context, Result};
+use reqwest::StatusCode;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Probe until it passes, or fail with the last probe's error once the timeout is up
+pub(crate) async fn wait(config: &ReadinessConfig) -> Result<()> {
+    let client = client(config)?;
+    let deadline = Instant::now() + Duration::from_secs(config.timeout);
+    let interval = Duration::from_secs(config.interval);
+
+    loop {
+        let e = match probe(&client, config).await {
+            Ok(()) => {
+                log::info!("container is ready");
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        if Instant::now() + interval > deadline {
+            return Err(e.context(format!("not ready after {}s", config.timeout)));
+        }
+
+        log::debug!("container not ready yet: {:#}", e);
+        tokio::time::delay_for(interval).await;
+    }
+}
+
+/// HTTP client for probing, timing out requests before the next probe is due
+pub(crate) fn client(config: &ReadinessConfig) -> Result<reqwest::Client> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.interval.max(1)))
+        .build()?;
+    Ok(client)
+}
+
+/// Probe once
+pub(crate) async fn probe(client: &reqwest::Client, config: &ReadinessConfig) -> Result<()> {
+    if let Some(url) = &config.http {
+        let res = client
+            .get(url)
+            .send()
+            .await
+            .with_context(|| format!("requesting {}", url))?;
+        let status = res.status();
+        if !status_ok(status, config.expected_status) {
+            anyhow::bail!("{} returned {}", url, status);
+        }
+    }
+    if let Some(address) = &config.tcp {
+        let address: SocketAddr = address.parse()?;
+        let timeout = Duration::from_secs(config.interval.max(1));
+        // std's connect takes a timeout, which tokio's does not
+        tokio::task::spawn_blocking(move || {
+            std::net::TcpStream::connect_timeout(&address, timeout)
+        })
+        .await?
+        .with_context(|| format!("connecting to {}", address))?;
+    }
+    Ok(())
+}
+
+fn status_ok(status: StatusCode, expected: Option<u16>) -> bool {
+    match expected {
+        Some(expected) => status.as_u16() == expected,
+        None => status.is_success(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp_config(address: String) -> ReadinessConfig {
+        ReadinessConfig {
+            http: None,
+            tcp: Some(address),
+            expected_status: None,
+            timeout: 0,
+            interval: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_probe() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        assert!(wait(&tcp_config(address.clone())).await.is_ok());
+
+        drop(listener);
+        assert!(wait(&tcp_config(address)).await.is_err());
+    }
+
+    #[test]
+    fn test_status_ok() {
+        assert!(status_ok(StatusCode::OK, None));
+        assert!(status_ok(StatusCode::NO_CONTENT, None));
+        assert!(!status_ok(StatusCode::SERVICE_UNAVAILABLE, None));
+        assert!(status_ok(StatusCode::OK, Some(200)));
+        assert!(!status_ok(StatusCode::NO_CONTENT, Some(200)));
+    }
+}
@@ -0,0 +1,169 @@
+//! Replicas of a service
+//!
+//! With `replicas` above one, the service runs as `name-1` to `name-N`. Each replica's host ports
+//! are shifted up by `replica_port_offset` from the previous replica's so they do not clash, and
+//! so is the port the readiness probe checks. A single replica keeps the plain container name and
+//! the configured ports.
+
+use crate::config::{ContainerConfig, PortConfig, ReadinessConfig};
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Replica {
+    pub(crate) name: String,
+    pub(crate) ports: Vec<PortConfig>,
+    /// How far this replica's host ports are shifted from the configured ones
+    pub(crate) port_offset: u16,
+}
+
+/// Every replica the service should be running, failing if their host ports would clash
+pub(crate) fn plan(container: &ContainerConfig) -> Result<Vec<Replica>> {
+    if container.replicas <= 1 {
+        return Ok(vec![Replica {
+            name: container.name.clone(),
+            ports: container.ports.clone(),
+            port_offset: 0,
+        }]);
+    }
+
+    let mut replicas = Vec::new();
+    let mut taken = HashSet::new();
+    for index in 0..container.replicas {
+        let name = format!("{}-{}", container.name, index + 1);
+        let port_offset = index
+            .checked_mul(container.replica_port_offset)
+            .context("replica port offset is out of range")?;
+        let ports = container
+            .ports
+            .iter()
+            .map(|port| port.shifted(port_offset))
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("ports for {}", name))?;
+
+        for port in &ports {
+            for (host, _) in port.pairs() {
+                if !taken.insert((port.protocol.to_string(), host)) {
+                    anyhow::bail!(
+                        "host port {}/{} is used by more than one replica, increase \
+                         replica_port_offset",
+                        host,
+                        port.protocol
+                    );
+                }
+            }
+        }
+
+        replicas.push(Replica {
+            name,
+            ports,
+            port_offset,
+        });
+    }
+    Ok(replicas)
+}
+
+/// Which replica of `service` a container named `name` is: 0 for the plain name, `N` for
+/// `name-N`, and `None` for anything else, such as a canary
+pub(crate) fn index(service: &str, name: &str) -> Option<u16> {
+    if name == service {
+        return Some(0);
+    }
+    name.strip_prefix(service)?.strip_prefix('-')?.parse().ok()
+}
+
+/// The readiness probe for a replica, pointed at its shifted port
+pub(crate) fn readiness(config: &ReadinessConfig, replica: &Replica) -> Result<ReadinessConfig> {
+    let offset = replica.port_offset;
+    let mut config = config.clone();
+    if offset == 0 {
+        return Ok(config);
+    }
+
+    if let Some(url) = &config.http {
+        let mut parsed = reqwest::Url::parse(url).with_context(|| format!("parsing {}", url))?;
+        let port = parsed
+            .port_or_known_default()
+            .with_context(|| format!("{} has no port", url))?;
+        let port = port
+            .checked_add(offset)
+            .context("probe port out of range")?;
+        parsed
+            .set_port(Some(port))
+            .map_err(|_| anyhow::anyhow!("cannot set a port on {}", url))?;
+        config.http = Some(parsed.to_string());
+    }
+    if let Some(address) = &config.tcp {
+        let mut parsed: SocketAddr = address.parse()?;
+        let port = parsed
+            .port()
+            .checked_add(offset)
+            .context("probe port out of range")?;
+        parsed.set_port(port);
+        config.tcp = Some(parsed.to_string());
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(replicas: u16, replica_port_offset: u16) -> ContainerConfig {
+        let ports = vec![toml::from_str("host = \"5020-5021\"\ntarget = \"80-81\"").unwrap()];
+        ContainerConfig {
+            name: "foo".to_string(),
+            ports,
+            replicas,
+            replica_port_offset,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_single_replica() {
+        let replicas = plan(&container(1, 1)).unwrap();
+
+        assert_eq!(replicas.len(), 1);
+        assert_eq!(replicas[0].name, "foo");
+        assert_eq!(replicas[0].ports[0].host.start, 5020);
+    }
+
+    #[test]
+    fn test_replica_ports() {
+        let replicas = plan(&container(3, 10)).unwrap();
+
+        let names: Vec<&str> = replicas.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["foo-1", "foo-2", "foo-3"]);
+        let hosts: Vec<String> = replicas.iter().map(|r| r.ports[0].to_string()).collect();
+        assert_eq!(
+            hosts,
+            vec![
+                "5020-5021:80-81/tcp",
+                "5030-5031:80-81/tcp",
+                "5040-5041:80-81/tcp"
+            ]
+        );
+
+        // A range of two ports needs an offset of at least two
+        assert!(plan(&container(2, 1)).is_err());
+        assert!(plan(&container(2, u16::MAX)).is_err());
+    }
+
+    #[test]
+    fn test_replica_readiness() {
+        let replica = &plan(&container(2, 10)).unwrap()[1];
+        let config = ReadinessConfig {
+            http: Some("http://127.0.0.1/health".to_string()),
+            tcp: Some("127.0.0.1:5020".to_string()),
+            expected_status: None,
+            timeout: 60,
+            interval: 2,
+        };
+
+        let shifted = readiness(&config, replica).unwrap();
+        assert_eq!(shifted.http.as_deref(), Some("http://127.0.0.1:90/health"));
+        assert_eq!(shifted.tcp.as_deref(), Some("127.0.0.1:5030"));
+    }
+}
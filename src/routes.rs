@@ -1,30 +1,198 @@
+use crate::activity::Publisher;
+use crate::auth::Guard;
+use crate::config::{HeartbeatConfig, Scope};
+use crate::dockerclient::DockerApi;
 use crate::gitlab::Event;
 use crate::handlers;
+use crate::state::SharedState;
 use crate::Message;
 use tokio::sync::mpsc::UnboundedSender;
+use uuid::Uuid;
 use warp::filters::header::optional;
 use warp::Filter;
 
-pub(crate) fn build(
+pub(crate) fn build<D>(
     tx: UnboundedSender<Message>,
+    state: SharedState,
+    activity: Publisher,
+    docker: D,
     validation_key: Option<String>,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    trigger(tx.clone()).or(webhook(tx, validation_key))
+    heartbeat_config: &HeartbeatConfig,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+where
+    D: DockerApi + Clone + Send + Sync + 'static,
+{
+    let guard = Guard::new(state.clone());
+
+    heartbeat(state.clone(), heartbeat_config, &guard)
+        .or(dashboard())
+        .or(trigger(tx.clone(), &guard))
+        .or(rollback(tx.clone(), &guard))
+        .or(status(state.clone(), &guard))
+        .or(history(state.clone(), &guard))
+        .or(deployment(state.clone(), &guard))
+        .or(audit(state.clone(), &guard))
+        .or(logs(state.clone(), docker, &guard))
+        .or(events(activity, &guard))
+        .or(metrics(&guard))
+        .or(webhook(tx, state, validation_key))
+        .recover(handlers::handle_rejection)
+}
+
+/// GET /, the one route without a token, as the page itself holds nothing and asks for a token
+/// to fetch the rest
+pub(crate) fn dashboard() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path::end()
+        .and(warp::get())
+        .and_then(handlers::handle_dashboard)
 }
 
 /// POST /api/trigger
 pub(crate) fn trigger(
     tx: UnboundedSender<Message>,
+    guard: &Guard,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("trigger")
         .and(warp::post())
+        .and(guard.require(Scope::Deploy))
         .and(with_inbox(tx))
         .and_then(handlers::handle_trigger)
 }
 
+/// POST /api/rollback
+pub(crate) fn rollback(
+    tx: UnboundedSender<Message>,
+    guard: &Guard,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("rollback")
+        .and(warp::post())
+        .and(guard.require(Scope::Rollback))
+        .and(with_inbox(tx))
+        .and_then(handlers::handle_rollback)
+}
+
+/// GET /api/status
+pub(crate) fn status(
+    state: SharedState,
+    guard: &Guard,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("status")
+        .and(warp::get())
+        .and(guard.require(Scope::Read))
+        .and(with_state(state))
+        .and_then(handlers::handle_status)
+}
+
+/// GET /api/history
+pub(crate) fn history(
+    state: SharedState,
+    guard: &Guard,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("history")
+        .and(warp::get())
+        .and(guard.require(Scope::Read))
+        .and(with_state(state))
+        .and_then(handlers::handle_history)
+}
+
+/// GET /api/deployments/{id}
+pub(crate) fn deployment(
+    state: SharedState,
+    guard: &Guard,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("deployments" / Uuid)
+        .and(warp::get())
+        .and(guard.require(Scope::Read))
+        .and(with_state(state))
+        .and_then(handlers::handle_deployment)
+}
+
+/// GET /api/audit
+pub(crate) fn audit(
+    state: SharedState,
+    guard: &Guard,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("audit")
+        .and(warp::get())
+        .and(guard.require(Scope::Admin))
+        .and(with_state(state))
+        .and_then(handlers::handle_audit)
+}
+
+/// GET /api/logs?follow=true&tail=N&since=T
+pub(crate) fn logs<D>(
+    state: SharedState,
+    docker: D,
+    guard: &Guard,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+where
+    D: DockerApi + Clone + Send + Sync + 'static,
+{
+    warp::path!("logs")
+        .and(warp::get())
+        .and(guard.require(Scope::Read))
+        .and(warp::query::<handlers::LogsQuery>())
+        .and(warp::header::optional::<String>("accept"))
+        .and(with_state(state))
+        .and(with_docker(docker))
+        .and_then(handlers::handle_logs)
+}
+
+/// GET /api/events
+pub(crate) fn events(
+    activity: Publisher,
+    guard: &Guard,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("events")
+        .and(warp::get())
+        .and(guard.require(Scope::Read))
+        .and(warp::any().map(move || activity.clone()))
+        .and_then(handlers::handle_events)
+}
+
+/// GET at the configured heartbeat endpoint
+pub(crate) fn heartbeat(
+    state: SharedState,
+    config: &HeartbeatConfig,
+    guard: &Guard,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let endpoint = format!("/{}", config.endpoint.trim_start_matches('/'));
+    let max_age = crate::heartbeat::max_age(config);
+
+    warp::get()
+        .and(warp::path::full())
+        .and_then(move |path: warp::path::FullPath| {
+            let matches = path.as_str() == endpoint;
+            async move {
+                if matches {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one()
+        .and(guard.require(Scope::Read))
+        .and(with_state(state))
+        .and(warp::any().map(move || max_age))
+        .and_then(handlers::handle_heartbeat)
+}
+
+/// GET /api/metrics
+pub(crate) fn metrics(
+    guard: &Guard,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and(guard.require(Scope::Read))
+        .and_then(handlers::handle_metrics)
+}
+
 /// POST /api/webhook
 pub(crate) fn webhook(
     tx: UnboundedSender<Message>,
+    state: SharedState,
     validation_key: Option<String>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     let key = warp::any().map(move || validation_key.clone());
@@ -34,6 +202,7 @@ pub(crate) fn webhook(
         .and(optional::<String>("X-Gitlab-Token"))
         .and(json_body())
         .and(with_inbox(tx))
+        .and(with_state(state))
         .and(key)
         .and_then(handlers::handle_webhook)
 }
@@ -44,6 +213,21 @@ fn with_inbox(
     warp::any().map(move || tx.clone())
 }
 
+fn with_state(
+    state: SharedState,
+) -> impl Filter<Extract = (SharedState,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
+
+fn with_docker<D>(
+    docker: D,
+) -> impl Filter<Extract = (D,), Error = std::convert::Infallible> + Clone
+where
+    D: DockerApi + Clone + Send + Sync + 'static,
+{
+    warp::any().map(move || docker.clone())
+}
+
 fn json_body() -> impl Filter<Extract = (Event,), Error = warp::Rejection> + Clone {
     warp::body::json()
 }
@@ -0,0 +1,324 @@
+//! Sidecar containers
+//!
+//! Companion containers a service declares with `[[sidecars]]`, such as a cache or a log shipper.
+//! They are started in dependency order before the service's own containers, and stopped in
+//! reverse order. A sidecar is only recreated when its configuration changes, when it is not
+//! running, or when a sidecar it depends on is recreated, so it keeps running across the service's
+//! deploys.
+
+use crate::config::{ContainerConfig, NetworkConfig, SidecarConfig, StopConfig};
+use crate::dockerclient::{
+    ContainerFilter, ContainerSummary, CreateImageOptions, DockerApi, RunContainerOptions,
+};
+use crate::labels;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+
+/// e.g. `foobar-redis`
+pub(crate) fn container_name(service: &str, sidecar: &SidecarConfig) -> String {
+    format!("{}-{}", service, sidecar.name)
+}
+
+/// The sidecars ordered so that each comes after everything it depends on
+pub(crate) fn order(sidecars: &[SidecarConfig]) -> Result<Vec<&SidecarConfig>> {
+    let mut by_name = HashMap::new();
+    for sidecar in sidecars {
+        if by_name.insert(sidecar.name.as_str(), sidecar).is_some() {
+            anyhow::bail!("sidecar {} is declared twice", sidecar.name);
+        }
+    }
+
+    let mut ordered = Vec::new();
+    let mut done = HashSet::new();
+    for sidecar in sidecars {
+        visit(sidecar, &by_name, &mut Vec::new(), &mut done, &mut ordered)?;
+    }
+    Ok(ordered)
+}
+
+/// Depth-first walk of `sidecar`'s dependencies, with `path` holding the sidecars being visited to
+/// catch cycles
+fn visit<'a>(
+    sidecar: &'a SidecarConfig,
+    by_name: &HashMap<&str, &'a SidecarConfig>,
+    path: &mut Vec<&'a str>,
+    done: &mut HashSet<&'a str>,
+    ordered: &mut Vec<&'a SidecarConfig>,
+) -> Result<()> {
+    let name = sidecar.name.as_str();
+    if done.contains(name) {
+        return Ok(());
+    }
+    if path.contains(&name) {
+        anyhow::bail!("dependency cycle: {} -> {}", path.join(" -> "), name);
+    }
+
+    path.push(name);
+    for dependency in &sidecar.depends_on {
+        let dependency = by_name
+            .get(dependency.as_str())
+            .with_context(|| format!("sidecar {} depends on unknown {}", name, dependency))?;
+        visit(dependency, by_name, path, done, ordered)?;
+    }
+    path.pop();
+
+    done.insert(name);
+    ordered.push(sidecar);
+    Ok(())
+}
+
+pub(crate) async fn pull<D: DockerApi>(docker: &D, sidecars: &[SidecarConfig]) -> Result<()> {
+    for sidecar in sidecars {
+        log::info!("pulling sidecar image {}", sidecar.reference());
+        docker
+            .create_image(CreateImageOptions {
+                from_image: &sidecar.image,
+                tag: &sidecar.tag,
+            })
+            .await
+            .with_context(|| format!("pulling image for sidecar {}", sidecar.name))?;
+    }
+    Ok(())
+}
+
+/// Bring the service's sidecars in line with the config, removing the ones it no longer declares.
+/// With `restart_only`, only sidecars that are not running are started, and anything else waits
+/// for the next deploy.
+pub(crate) async fn ensure<D: DockerApi>(
+    docker: &D,
+    service: &ContainerConfig,
+    sidecars: &[SidecarConfig],
+    restart_only: bool,
+) -> Result<()> {
+    let ordered = order(sidecars)?;
+    let filter = format!("{}={}", labels::SIDECAR_OF, service.name);
+    let existing = docker
+        .list_containers(ContainerFilter::Label(&filter))
+        .await
+        .context("listing sidecars")?;
+
+    let undeclared = existing.iter().filter(|c| {
+        !ordered
+            .iter()
+            .any(|s| container_name(&service.name, s) == c.name)
+    });
+    for container in undeclared.filter(|_| !restart_only) {
+        remove(docker, container, StopConfig::default().timeout).await?;
+    }
+
+    let stale = stale(&service.name, &ordered, &existing, restart_only);
+    let find = |sidecar: &SidecarConfig| {
+        let name = container_name(&service.name, sidecar);
+        existing.iter().find(move |c| c.name == name)
+    };
+    for sidecar in ordered
+        .iter()
+        .rev()
+        .filter(|s| stale.contains(s.name.as_str()))
+    {
+        if let Some(container) = find(sidecar) {
+            remove(docker, container, sidecar.stop.timeout).await?;
+        }
+    }
+    for sidecar in ordered.iter().filter(|s| stale.contains(s.name.as_str())) {
+        start(docker, service, sidecar)
+            .await
+            .with_context(|| format!("starting sidecar {}", sidecar.name))?;
+    }
+    Ok(())
+}
+
+/// Names of the sidecars that need (re)starting
+fn stale<'a>(
+    service: &str,
+    ordered: &[&'a SidecarConfig],
+    existing: &[ContainerSummary],
+    restart_only: bool,
+) -> HashSet<&'a str> {
+    let mut stale = HashSet::new();
+    for sidecar in ordered {
+        let name = container_name(service, sidecar);
+        let needs_start = match existing.iter().find(|c| c.name == name) {
+            None => true,
+            Some(container) if !container.running => true,
+            Some(container) => {
+                !restart_only && container.labels.get(labels::CONFIG_HASH) != Some(&sidecar.hash)
+            }
+        };
+        // Dependencies come first, so anything this depends on has already been decided
+        let dependency_stale = sidecar
+            .depends_on
+            .iter()
+            .any(|d| stale.contains(d.as_str()));
+        if needs_start || dependency_stale {
+            stale.insert(sidecar.name.as_str());
+        }
+    }
+    stale
+}
+
+async fn remove<D: DockerApi>(
+    docker: &D,
+    container: &ContainerSummary,
+    timeout: u64,
+) -> Result<()> {
+    log::info!("removing sidecar {}", container.name);
+    if container.running {
+        if let Err(e) = docker.stop_container(&container.id, timeout).await {
+            log::warn!(
+                "error stopping sidecar {}, forcing removal: {:#}",
+                container.name,
+                e
+            );
+        }
+    }
+    docker
+        .remove_container(&container.id)
+        .await
+        .with_context(|| format!("removing sidecar {}", container.name))
+}
+
+async fn start<D: DockerApi>(
+    docker: &D,
+    service: &ContainerConfig,
+    sidecar: &SidecarConfig,
+) -> Result<()> {
+    let name = container_name(&service.name, sidecar);
+    log::info!("starting sidecar {}", name);
+
+    // On the service's networks the sidecar is reachable by its own name, and must not take the
+    // service's aliases or addresses
+    let (networks, network_mode) = match &sidecar.networks {
+        Some(networks) => (networks.clone(), None),
+        None => {
+            let networks = service
+                .networks
+                .iter()
+                .map(|network| NetworkConfig {
+                    aliases: vec![sidecar.name.clone()],
+                    ..network.anonymous()
+                })
+                .collect();
+            (networks, service.network_mode.clone())
+        }
+    };
+    for network in &networks {
+        docker
+            .ensure_network(network)
+            .await
+            .with_context(|| format!("preparing network {}", network.name))?;
+    }
+
+    let mut sidecar_labels: HashMap<String, String> = sidecar
+        .labels
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    sidecar_labels.insert(labels::SIDECAR_OF.to_string(), service.name.clone());
+    sidecar_labels.insert(labels::CONFIG_HASH.to_string(), sidecar.hash.clone());
+
+    let res = docker
+        .run_container(RunContainerOptions {
+            name: &name,
+            image: &sidecar.reference(),
+            cmd: sidecar.command.iter().map(String::as_str).collect(),
+            ports: sidecar.ports.clone(),
+            mounts: sidecar.mounts.clone(),
+            networks,
+            network_mode,
+            runtime: sidecar.runtime.clone(),
+            labels: sidecar_labels,
+            env: sidecar.env.clone(),
+            healthcheck: None,
+            stop: sidecar.stop.clone(),
+        })
+        .await?;
+
+    for warning in res.warnings {
+        log::warn!("run_container warning: {}", warning);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sidecar(name: &str, depends_on: &[&str]) -> SidecarConfig {
+        let mut sidecar: SidecarConfig =
+            toml::from_str(&format!("name = \"{}\"\nimage = \"{}\"", name, name)).unwrap();
+        sidecar.depends_on = depends_on.iter().map(|d| d.to_string()).collect();
+        sidecar.hash = format!("{}-hash", name);
+        sidecar
+    }
+
+    fn names(ordered: &[&SidecarConfig]) -> Vec<String> {
+        ordered.iter().map(|s| s.name.clone()).collect()
+    }
+
+    #[test]
+    fn test_order() {
+        let sidecars = vec![
+            sidecar("shipper", &["redis", "proxy"]),
+            sidecar("proxy", &["redis"]),
+            sidecar("redis", &[]),
+        ];
+
+        assert_eq!(
+            names(&order(&sidecars).unwrap()),
+            vec!["redis", "proxy", "shipper"]
+        );
+    }
+
+    #[test]
+    fn test_invalid_order() {
+        let cycle = vec![sidecar("a", &["b"]), sidecar("b", &["a"])];
+        let e = order(&cycle).unwrap_err();
+        assert_eq!(e.to_string(), "dependency cycle: a -> b -> a");
+
+        assert!(order(&[sidecar("a", &["missing"])]).is_err());
+        assert!(order(&[sidecar("a", &[]), sidecar("a", &[])]).is_err());
+    }
+
+    #[test]
+    fn test_stale() {
+        let sidecars = vec![
+            sidecar("redis", &[]),
+            sidecar("proxy", &["redis"]),
+            sidecar("shipper", &[]),
+        ];
+        let ordered = order(&sidecars).unwrap();
+        let container = |name: &str, running: bool, hash: &str| ContainerSummary {
+            id: name.to_string(),
+            name: format!("foo-{}", name),
+            running,
+            labels: vec![(labels::CONFIG_HASH.to_string(), hash.to_string())]
+                .into_iter()
+                .collect(),
+            created: None,
+        };
+
+        // A changed sidecar takes down whatever depends on it
+        let existing = vec![
+            container("redis", true, "old"),
+            container("proxy", true, "proxy-hash"),
+            container("shipper", true, "shipper-hash"),
+        ];
+        let mut changed: Vec<&str> = stale("foo", &ordered, &existing, false)
+            .into_iter()
+            .collect();
+        changed.sort_unstable();
+        assert_eq!(changed, vec!["proxy", "redis"]);
+        assert!(stale("foo", &ordered, &existing, true).is_empty());
+
+        let existing = vec![
+            container("redis", true, "redis-hash"),
+            container("proxy", false, "proxy-hash"),
+        ];
+        let mut restarted: Vec<&str> = stale("foo", &ordered, &existing, true)
+            .into_iter()
+            .collect();
+        restarted.sort_unstable();
+        assert_eq!(restarted, vec!["proxy", "shipper"]);
+    }
+}
@@ -0,0 +1,492 @@
+//! State shared between the controller and the web server
+//!
+//! The controller is the only writer, apart from the audit log which the API's auth guard appends
+//! to; the HTTP handlers take a read lock to answer status and history queries without having to
+//! round-trip through the controller's message channel.
+
+use crate::auth::AuditEntry;
+use crate::config::AuthConfig;
+use crate::deploylog::{DeployLog, LogLine};
+use crate::labels::ManagedContainer;
+use crate::replicas;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+/// How many deployments to remember
+const MAX_HISTORY: usize = 50;
+
+/// How many audit entries to remember
+const MAX_AUDIT: usize = 500;
+
+pub(crate) type SharedState = Arc<RwLock<State>>;
+
+/// What caused a deployment to happen
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TriggerSource {
+    /// A manual request through the `/trigger` endpoint
+    Api,
+    /// A gitlab pipeline webhook
+    Webhook,
+    /// The poll loop found the container missing
+    Poll,
+    /// A manual request through the `/rollback` endpoint
+    Rollback,
+}
+
+impl fmt::Display for TriggerSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            TriggerSource::Api => "api",
+            TriggerSource::Webhook => "webhook",
+            TriggerSource::Poll => "poll",
+            TriggerSource::Rollback => "rollback",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Outcome {
+    InProgress,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Deployment {
+    pub(crate) id: Uuid,
+    pub(crate) trigger: TriggerSource,
+    pub(crate) image: String,
+    pub(crate) image_id: Option<String>,
+    pub(crate) image_digest: Option<String>,
+    /// The commit the image was built from, for images built by the daemon
+    #[serde(default)]
+    pub(crate) commit: Option<String>,
+    pub(crate) started_at: DateTime<Utc>,
+    pub(crate) finished_at: Option<DateTime<Utc>>,
+    pub(crate) outcome: Outcome,
+    pub(crate) error: Option<String>,
+    /// Why the previous container died, for deployments started by the poll loop finding it dead
+    #[serde(default)]
+    pub(crate) crash: Option<CrashReport>,
+}
+
+/// How a container that was found dead exited
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct CrashReport {
+    pub(crate) exit_code: i64,
+    pub(crate) oom_killed: bool,
+    pub(crate) finished_at: Option<DateTime<Utc>>,
+    /// Docker's own error, e.g. when the entrypoint could not be run
+    pub(crate) error: Option<String>,
+    /// The last lines the container logged
+    pub(crate) logs: Vec<String>,
+}
+
+impl CrashReport {
+    /// e.g. "exited with status 137 (out of memory)"
+    pub(crate) fn describe(&self) -> String {
+        let mut description = format!("exited with status {}", self.exit_code);
+        if self.oom_killed {
+            description.push_str(" (out of memory)");
+        }
+        if let Some(error) = &self.error {
+            description.push_str(&format!(": {}", error));
+        }
+        description
+    }
+}
+
+impl Deployment {
+    pub(crate) fn new(trigger: TriggerSource, image: impl Into<String>) -> Self {
+        Deployment {
+            id: Uuid::new_v4(),
+            trigger,
+            image: image.into(),
+            image_id: None,
+            image_digest: None,
+            commit: None,
+            started_at: Utc::now(),
+            finished_at: None,
+            outcome: Outcome::InProgress,
+            error: None,
+            crash: None,
+        }
+    }
+}
+
+/// A deployment with everything it logged, as returned by `/deployments/{id}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct DeploymentDetails {
+    #[serde(flatten)]
+    pub(crate) deployment: Deployment,
+    pub(crate) log: Vec<LogLine>,
+}
+
+/// Summary of the managed container, as returned by `/status`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub(crate) struct Status {
+    pub(crate) container: String,
+    pub(crate) image: String,
+    pub(crate) running: bool,
+    pub(crate) last_checked: Option<DateTime<Utc>>,
+    /// The container's last reported health check status, if it has a health check
+    pub(crate) health: Option<String>,
+    pub(crate) last_deployment: Option<Deployment>,
+    /// The labelled container currently standing in for the configured one, or the first replica,
+    /// if any
+    pub(crate) managed: Option<ManagedContainer>,
+    /// The service's replicas in order, leaving out a canary
+    #[serde(default)]
+    pub(crate) replicas: Vec<ManagedContainer>,
+    /// Whether any replica was started with a different `[container]` config to the current one
+    pub(crate) config_changed: bool,
+    /// All of the service's labelled containers: its replicas, and a canary while one is soaking
+    #[serde(default)]
+    pub(crate) containers: Vec<ManagedContainer>,
+    /// Labelled containers belonging to other services, e.g. ones left behind by a rename
+    pub(crate) orphans: Vec<ManagedContainer>,
+}
+
+/// Marks the controller busy with a deployment until dropped, so that one which panics does not
+/// leave it marked busy
+pub(crate) struct Busy(SharedState);
+
+impl Busy {
+    pub(crate) fn start(state: &SharedState) -> Self {
+        state.write().unwrap().busy_since = Some(Utc::now());
+        Busy(state.clone())
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.0.write() {
+            state.busy_since = None;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct State {
+    pub(crate) container: String,
+    pub(crate) image: String,
+    pub(crate) running: bool,
+    pub(crate) last_checked: Option<DateTime<Utc>>,
+    pub(crate) health: Option<String>,
+    /// When the controller last handled a heartbeat message
+    pub(crate) last_heartbeat: Option<DateTime<Utc>>,
+    /// When the controller started the deployment it is running, during which it handles no other
+    /// messages
+    pub(crate) busy_since: Option<DateTime<Utc>>,
+    /// Hash of the current `[container]` config
+    pub(crate) config_hash: String,
+    /// Every labelled container found at the last check
+    pub(crate) containers: Vec<ManagedContainer>,
+    /// The API's tokens, `None` when it is open
+    pub(crate) auth: Option<AuthConfig>,
+    /// Newest first
+    audit: Vec<AuditEntry>,
+    /// Newest first
+    history: Vec<Deployment>,
+    /// What each deployment in `history` logged
+    logs: HashMap<Uuid, DeployLog>,
+}
+
+impl State {
+    pub(crate) fn new(container: impl Into<String>, image: impl Into<String>) -> Self {
+        State {
+            container: container.into(),
+            image: image.into(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn shared(self) -> SharedState {
+        Arc::new(RwLock::new(self))
+    }
+
+    pub(crate) fn status(&self) -> Status {
+        let mut replicas: Vec<(u16, ManagedContainer)> = self
+            .containers
+            .iter()
+            .filter(|c| c.service == self.container)
+            .filter_map(|c| Some((replicas::index(&self.container, &c.name)?, c.clone())))
+            .collect();
+        replicas.sort_by_key(|(index, _)| *index);
+        let replicas: Vec<ManagedContainer> = replicas.into_iter().map(|(_, c)| c).collect();
+        let config_changed = replicas
+            .iter()
+            .any(|c| c.config_hash.as_deref() != Some(self.config_hash.as_str()));
+
+        Status {
+            container: self.container.clone(),
+            image: self.image.clone(),
+            running: self.running,
+            last_checked: self.last_checked,
+            health: self.health.clone(),
+            last_deployment: self.history.first().cloned(),
+            managed: replicas.first().cloned(),
+            replicas,
+            config_changed,
+            containers: self
+                .containers
+                .iter()
+                .filter(|c| c.service == self.container)
+                .cloned()
+                .collect(),
+            orphans: self
+                .containers
+                .iter()
+                .filter(|c| c.service != self.container)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub(crate) fn history(&self) -> &[Deployment] {
+        &self.history
+    }
+
+    pub(crate) fn audit(&self) -> &[AuditEntry] {
+        &self.audit
+    }
+
+    pub(crate) fn record_audit(&mut self, entry: AuditEntry) {
+        self.audit.insert(0, entry);
+        self.audit.truncate(MAX_AUDIT);
+    }
+
+    /// Whether the controller has handled a heartbeat recently enough to be considered alive
+    pub(crate) fn is_responsive(&self, max_age: Duration) -> bool {
+        self.last_heartbeat
+            .is_some_and(|t| Utc::now() - t <= max_age)
+    }
+
+    /// Whether the controller is responsive, or busy with a deployment rather than stuck
+    pub(crate) fn is_alive(&self, max_age: Duration) -> bool {
+        self.is_responsive(max_age) || self.busy_since.is_some()
+    }
+
+    /// Record a new deployment, or replace the existing record with the same id
+    pub(crate) fn record(&mut self, deployment: Deployment) {
+        match self.history.iter_mut().find(|d| d.id == deployment.id) {
+            Some(existing) => *existing = deployment,
+            None => {
+                self.history.insert(0, deployment);
+                self.history.truncate(MAX_HISTORY);
+                let history = &self.history;
+                self.logs
+                    .retain(|id, _| history.iter().any(|d| d.id == *id));
+            }
+        }
+    }
+
+    /// Keep the log a deployment is writing to, to be returned with it
+    pub(crate) fn attach_log(&mut self, id: Uuid, log: DeployLog) {
+        if self.history.iter().any(|d| d.id == id) {
+            self.logs.insert(id, log);
+        }
+    }
+
+    pub(crate) fn deployment(&self, id: Uuid) -> Option<DeploymentDetails> {
+        let deployment = self.history.iter().find(|d| d.id == id)?;
+        Some(DeploymentDetails {
+            deployment: deployment.clone(),
+            log: self.logs.get(&id).map(DeployLog::lines).unwrap_or_default(),
+        })
+    }
+
+    /// The image id of the newest successful deployment
+    pub(crate) fn last_succeeded_image(&self) -> Option<&str> {
+        self.history
+            .iter()
+            .filter(|d| d.outcome == Outcome::Succeeded)
+            .find_map(|d| d.image_id.as_deref())
+    }
+
+    /// The image id to go back to: the newest successful deployment that is running a different
+    /// image to the current one
+    pub(crate) fn rollback_target(&self) -> Option<&str> {
+        let mut succeeded = self
+            .history
+            .iter()
+            .filter(|d| d.outcome == Outcome::Succeeded)
+            .filter_map(|d| d.image_id.as_deref());
+
+        let current = succeeded.next()?;
+        succeeded.find(|id| *id != current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment(image_id: &str, outcome: Outcome) -> Deployment {
+        let mut d = Deployment::new(TriggerSource::Api, "python:3.8");
+        d.image_id = Some(image_id.to_string());
+        d.outcome = outcome;
+        d
+    }
+
+    #[test]
+    fn test_record_replaces_existing() {
+        let mut state = State::default();
+        let mut d = deployment("sha256:a", Outcome::InProgress);
+        state.record(d.clone());
+
+        d.outcome = Outcome::Succeeded;
+        state.record(d);
+
+        assert_eq!(state.history().len(), 1);
+        assert_eq!(state.history()[0].outcome, Outcome::Succeeded);
+    }
+
+    #[test]
+    fn test_deployment_logs() {
+        let mut state = State::default();
+        let first = deployment("sha256:a", Outcome::Succeeded);
+        state.record(first.clone());
+        state.attach_log(first.id, DeployLog::default());
+        assert!(state.deployment(first.id).unwrap().log.is_empty());
+        assert!(state.deployment(Uuid::new_v4()).is_none());
+
+        // Logs go with their deployment once it falls out of the history
+        for _ in 0..MAX_HISTORY {
+            state.record(deployment("sha256:b", Outcome::Succeeded));
+        }
+        assert!(state.deployment(first.id).is_none());
+        assert!(state.logs.is_empty());
+    }
+
+    fn managed(service: &str, config_hash: &str) -> ManagedContainer {
+        ManagedContainer {
+            id: format!("{}-id", service),
+            name: service.to_string(),
+            service: service.to_string(),
+            running: true,
+            config_hash: Some(config_hash.to_string()),
+            image_digest: None,
+            deploy_id: None,
+            trigger: None,
+            created: None,
+        }
+    }
+
+    #[test]
+    fn test_status_containers() {
+        let mut state = State::new("foo", "python:3.8");
+        state.config_hash = "new".to_string();
+        state.containers = vec![managed("foo", "old"), managed("bar", "new")];
+
+        let status = state.status();
+
+        assert_eq!(status.managed.unwrap().id, "foo-id");
+        assert!(status.config_changed);
+        assert_eq!(status.containers.len(), 1);
+        assert_eq!(status.orphans.len(), 1);
+        assert_eq!(status.orphans[0].service, "bar");
+    }
+
+    #[test]
+    fn test_status_replicas() {
+        let mut state = State::new("foo", "python:3.8");
+        state.config_hash = "new".to_string();
+        let replica = |name: &str, config_hash: &str| ManagedContainer {
+            id: format!("{}-id", name),
+            name: name.to_string(),
+            ..managed("foo", config_hash)
+        };
+        state.containers = vec![
+            replica("foo-2", "old"),
+            replica("foo-1", "new"),
+            replica("foo-canary", "new"),
+        ];
+
+        let status = state.status();
+
+        let names: Vec<&str> = status.replicas.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["foo-1", "foo-2"]);
+        assert_eq!(status.managed.unwrap().id, "foo-1-id");
+        assert!(status.config_changed);
+        assert_eq!(status.containers.len(), 3);
+
+        state.containers.retain(|c| c.name != "foo-2");
+        assert!(!state.status().config_changed);
+    }
+
+    #[test]
+    fn test_describe_crash() {
+        let mut crash = CrashReport {
+            exit_code: 137,
+            oom_killed: true,
+            finished_at: None,
+            error: None,
+            logs: Vec::new(),
+        };
+        assert_eq!(crash.describe(), "exited with status 137 (out of memory)");
+
+        crash.oom_killed = false;
+        crash.error = Some("exec format error".to_string());
+        assert_eq!(
+            crash.describe(),
+            "exited with status 137: exec format error"
+        );
+    }
+
+    #[test]
+    fn test_is_responsive() {
+        let mut state = State::default();
+        assert!(!state.is_responsive(Duration::seconds(30)));
+
+        state.last_heartbeat = Some(Utc::now() - Duration::seconds(10));
+        assert!(state.is_responsive(Duration::seconds(30)));
+
+        state.last_heartbeat = Some(Utc::now() - Duration::seconds(60));
+        assert!(!state.is_responsive(Duration::seconds(30)));
+        assert!(!state.is_alive(Duration::seconds(30)));
+
+        let state = state.shared();
+        let busy = Busy::start(&state);
+        assert!(state.read().unwrap().is_alive(Duration::seconds(30)));
+        drop(busy);
+        assert!(!state.read().unwrap().is_alive(Duration::seconds(30)));
+    }
+
+    #[test]
+    fn test_rollback_target() {
+        let mut state = State::default();
+        state.record(deployment("sha256:a", Outcome::Succeeded));
+        state.record(deployment("sha256:b", Outcome::Failed));
+        state.record(deployment("sha256:c", Outcome::Succeeded));
+        state.record(deployment("sha256:c", Outcome::Succeeded));
+
+        assert_eq!(state.rollback_target(), Some("sha256:a"));
+    }
+
+    #[test]
+    fn test_last_succeeded_image() {
+        let mut state = State::default();
+        assert_eq!(state.last_succeeded_image(), None);
+
+        state.record(deployment("sha256:a", Outcome::Succeeded));
+        state.record(deployment("sha256:b", Outcome::Failed));
+
+        assert_eq!(state.last_succeeded_image(), Some("sha256:a"));
+    }
+
+    #[test]
+    fn test_no_rollback_target() {
+        let mut state = State::default();
+        state.record(deployment("sha256:a", Outcome::Succeeded));
+
+        assert_eq!(state.rollback_target(), None);
+    }
+}